@@ -1,13 +1,16 @@
 use std::fs;
 use std::io::{self, BufRead, BufReader, Read, Seek, Write};
-use std::os::unix::fs::symlink;
-use std::path::Path;
+use std::os::unix::fs::{symlink, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::process;
 use std::thread;
 use std::time;
 
 use anyhow::{Context, Result};
 use clap::{Arg, ArgMatches, Command};
 use glob::glob;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
 pub fn configure_app(app: Command) -> Command {
     return app
@@ -22,14 +25,26 @@ pub fn configure_app(app: Command) -> Command {
                 .required(true),
         )
         .subcommand(
-            Command::new("write").about("write STDIN to the log").arg(
-                Arg::new("max-segment")
-                    .short('m')
-                    .long("max-segment")
-                    .help("maximum size for each segment in MB")
-                    .default_value("100")
-                    .takes_value(true),
-            ),
+            Command::new("write")
+                .about("write STDIN to the log")
+                .arg(
+                    Arg::new("max-segment")
+                        .short('m')
+                        .long("max-segment")
+                        .help("maximum size for each segment in MB")
+                        .default_value("100")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("layout")
+                        .long("layout")
+                        .help(
+                            "how to arrange segments when creating a new log: all in \
+                            the log's directory, or nested in directories by offset",
+                        )
+                        .possible_values(["flat", "nested"])
+                        .takes_value(true),
+                ),
         )
         .subcommand(
             Command::new("read")
@@ -49,13 +64,72 @@ pub fn configure_app(app: Command) -> Command {
                         .help("wait for additional data to be appended to the log"),
                 )
                 .arg(Arg::new("track").short('t').long("track").help(
-                    "write the cursor of each line read to STDERR to help clients \
-                            resume reads",
+                    "write the cursor of each line (or batch) read to STDERR to help \
+                            clients resume reads",
                 ))
+                .arg(
+                    Arg::new("batch-lines")
+                        .long("batch-lines")
+                        .help(
+                            "write lines in batches, as JSON arrays, of at most this \
+                            many lines",
+                        )
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("batch-bytes")
+                        .long("batch-bytes")
+                        .help(
+                            "write lines in batches, as JSON arrays, of at most this \
+                            many bytes of log data",
+                        )
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("batch-wait")
+                        .long("batch-wait")
+                        .help(
+                            "when following, how long in ms to wait for a partial \
+                            batch to fill before writing it",
+                        )
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("group")
+                        .short('g')
+                        .long("group")
+                        .help(
+                            "read as a member of the named consumer group. Members \
+                            lease segments of the log from each other, so each line \
+                            is delivered to one member, and commit their progress to \
+                            the log's directory. Lines are committed as they're written \
+                            to STDOUT; with exec, once the command has handled them",
+                        )
+                        .conflicts_with_all(&[
+                            "cursor",
+                            "batch-lines",
+                            "batch-bytes",
+                            "batch-wait",
+                        ])
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("lease-timeout")
+                        .long("lease-timeout")
+                        .help(
+                            "seconds after which a group member's lease, if not \
+                            renewed, is considered abandoned and its uncommitted \
+                            lines are redelivered to another member",
+                        )
+                        .requires("group")
+                        .default_value("30")
+                        .takes_value(true),
+                )
                 .subcommand(
                     Command::new("exec")
                         .about(
-                            "execute a command for each line read from the log. \
+                            "execute a command for each line read from the log, \
+                            with the line on its STDIN. \
                             If the command exits with a 0 / successful error code, \
                             the cursor of the read line is written to STDERR. \
                             Otherwise the read will exit with the same error code.",
@@ -75,6 +149,80 @@ pub fn configure_app(app: Command) -> Command {
                                 .required(false),
                         ),
                 ),
+        )
+        .subcommand(
+            Command::new("cat")
+                .about(
+                    "read this and other logs to STDOUT, merged in order of a \
+                    timestamp field of their JSON lines",
+                )
+                .arg(
+                    Arg::new("paths")
+                        .index(1)
+                        .help("paths of the other logs to merge")
+                        .multiple_values(true),
+                )
+                .arg(
+                    Arg::new("field")
+                        .long("field")
+                        .help(
+                            "timestamp field to merge by, with dots for nested \
+                            fields. Numbers are seconds since the epoch, strings are \
+                            RFC 3339. Lines without one keep their place after the \
+                            line before them",
+                        )
+                        .default_value("timestamp")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("cursor")
+                        .short('c')
+                        .long("cursor")
+                        .help(
+                            "composite cursor to read from: a comma separated cursor \
+                            for each log, in the order the logs are given",
+                        )
+                        .takes_value(true),
+                )
+                .arg(Arg::new("track").short('t').long("track").help(
+                    "write the composite cursor of each line read to STDERR to help \
+                    clients resume reads",
+                )),
+        )
+        .subcommand(
+            Command::new("export")
+                .about("write a range of the log to STDOUT as an archive")
+                .arg(
+                    Arg::new("from")
+                        .long("from")
+                        .help("cursor to start the range from")
+                        .default_value("0")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("to")
+                        .long("to")
+                        .help(
+                            "cursor to end the range at. Defaults to the end of the log",
+                        )
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            Command::new("import")
+                .about("append archives read from STDIN to the log")
+                .arg(
+                    Arg::new("max-segment")
+                        .short('m')
+                        .long("max-segment")
+                        .help("maximum size for each segment in MB")
+                        .default_value("100")
+                        .takes_value(true),
+                )
+                .arg(Arg::new("force").long("force").help(
+                    "import an archive even if its range doesn't start where the log \
+                    currently ends",
+                )),
         );
 }
 
@@ -84,12 +232,112 @@ pub fn run(matches: &ArgMatches) -> Result<()> {
     match matches.subcommand() {
         Some(("write", matches)) => {
             let max_segment: u64 = matches.value_of_t("max-segment").unwrap();
+            match matches.value_of("layout") {
+                Some("flat") => set_layout(path, Layout::Flat)?,
+                Some("nested") => set_layout(path, Layout::Nested)?,
+                _ => (),
+            }
             run_write(io::stdin(), &path, max_segment * 1024 * 1024)?;
         }
         Some(("read", matches)) => {
             let cursor: u64 = matches.value_of_t("cursor").unwrap();
             let follow: bool = matches.is_present("follow");
 
+            let batch = if ["batch-lines", "batch-bytes", "batch-wait"]
+                .iter()
+                .any(|x| matches.is_present(x))
+            {
+                let wait: u64 = if matches.is_present("batch-wait") {
+                    matches
+                        .value_of_t("batch-wait")
+                        .unwrap_or_else(|e| e.exit())
+                } else {
+                    0
+                };
+                Some(Batch {
+                    lines: matches.is_present("batch-lines").then(|| {
+                        matches
+                            .value_of_t("batch-lines")
+                            .unwrap_or_else(|e| e.exit())
+                    }),
+                    bytes: matches.is_present("batch-bytes").then(|| {
+                        matches
+                            .value_of_t("batch-bytes")
+                            .unwrap_or_else(|e| e.exit())
+                    }),
+                    wait: time::Duration::from_millis(wait),
+                })
+            } else {
+                None
+            };
+
+            let exec: Option<Vec<String>> = match matches.subcommand() {
+                Some(("exec", matches)) => Some(
+                    matches
+                        .values_of("command")
+                        .into_iter()
+                        .chain(matches.values_of("arguments"))
+                        .flatten()
+                        .map(String::from)
+                        .collect(),
+                ),
+                _ => None,
+            };
+            anyhow::ensure!(
+                exec.is_none() || batch.is_none(),
+                "exec can't be used with batches"
+            );
+            let exec = exec.as_deref();
+
+            // the cursor of each line a command handles is always written
+            let mut stderr = io::stderr();
+            let track = if matches.is_present("track") || exec.is_some() {
+                Some(&mut stderr)
+            } else {
+                None
+            };
+
+            let mut stdout = io::stdout();
+            let code = if matches.is_present("group") {
+                let group: String = matches.value_of_t("group").unwrap();
+                let timeout: u64 = matches
+                    .value_of_t("lease-timeout")
+                    .unwrap_or_else(|e| e.exit());
+                let timeout = time::Duration::from_secs(timeout);
+                run_read_group(&mut stdout, path, &group, timeout, follow, exec, track)?
+            } else {
+                run_read(&mut stdout, &path, cursor, follow, batch, exec, track)?
+            };
+            if let Some(code) = code {
+                process::exit(code);
+            }
+        }
+        Some(("cat", matches)) => {
+            let mut paths = vec![path.to_path_buf()];
+            if let Some(others) = matches.values_of("paths") {
+                paths.extend(others.map(PathBuf::from));
+            }
+
+            let cursors = match matches.value_of("cursor") {
+                Some(cursor) => {
+                    let cursors = cursor
+                        .split(',')
+                        .map(|x| x.parse::<u64>())
+                        .collect::<Result<Vec<_>, _>>()
+                        .with_context(|| format!("invalid cursor `{}`", cursor))?;
+                    anyhow::ensure!(
+                        cursors.len() == paths.len(),
+                        "cursor `{}` doesn't have a cursor for each of the {} logs",
+                        cursor,
+                        paths.len()
+                    );
+                    cursors
+                }
+                None => vec![0; paths.len()],
+            };
+
+            let field: String = matches.value_of_t("field").unwrap();
+
             let mut stderr = io::stderr();
             let track = if matches.is_present("track") {
                 Some(&mut stderr)
@@ -97,7 +345,20 @@ pub fn run(matches: &ArgMatches) -> Result<()> {
                 None
             };
 
-            run_read(&mut io::stdout(), &path, cursor, follow, track)?;
+            run_cat(&mut io::stdout(), &paths, cursors, &field, track)?;
+        }
+        Some(("export", matches)) => {
+            let from: u64 = matches.value_of_t("from").unwrap_or_else(|e| e.exit());
+            let to: Option<u64> = matches
+                .is_present("to")
+                .then(|| matches.value_of_t("to").unwrap_or_else(|e| e.exit()));
+            run_export(&mut io::stdout(), path, from, to)?;
+        }
+        Some(("import", matches)) => {
+            let max_segment: u64 = matches.value_of_t("max-segment").unwrap();
+            let force = matches.is_present("force");
+            let stdin = io::stdin();
+            run_import(stdin.lock(), path, max_segment * 1024 * 1024, force)?;
         }
         _ => unreachable!(),
     }
@@ -105,6 +366,272 @@ pub fn run(matches: &ArgMatches) -> Result<()> {
     Ok(())
 }
 
+const MANIFEST: &str = "manifest.json";
+
+/// How segment files are arranged within a log's directory.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Layout {
+    /// every segment directly in the log's directory
+    Flat,
+    /// segments grouped into directories named for the leading 10 digits of their
+    /// offset, to keep directories small for logs with many segments
+    Nested,
+}
+
+impl Layout {
+    /// Returns the path, relative to the log's directory, of the segment starting at
+    /// `offset`.
+    fn segment(&self, offset: u64) -> String {
+        let name = format!("{:020}", offset);
+        match self {
+            Layout::Flat => name,
+            Layout::Nested => format!("{}/{}", &name[..10], name),
+        }
+    }
+}
+
+/// Describes the segments of a log, in order. The manifest is the source of truth
+/// for which segments exist: segments are created before they're added to it, and
+/// it's replaced atomically, so readers never see a partially created segment.
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    layout: Layout,
+    segments: Vec<SegmentEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SegmentEntry {
+    offset: u64,
+    /// relative to the log's directory
+    path: String,
+    /// only final once the segment is sealed. The active segment's size is as of
+    /// the last time the manifest was saved
+    size: u64,
+    sealed: bool,
+    /// CRC-32 of the contents of a sealed segment
+    checksum: Option<String>,
+}
+
+impl SegmentEntry {
+    /// Seals the segment, recording its final size and checksum.
+    fn seal(&mut self, path: &Path) -> Result<()> {
+        let mut fh = fs::File::open(path.join(&self.path))?;
+        let mut hasher = crc32fast::Hasher::new();
+        let mut size = 0;
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = fh.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            size += n as u64;
+        }
+        self.size = size;
+        self.sealed = true;
+        self.checksum = Some(format!("{:08x}", hasher.finalize()));
+        Ok(())
+    }
+}
+
+impl Manifest {
+    /// Loads the manifest of the log at `path`. Logs written before manifests were
+    /// introduced are described by scanning their directory for segments.
+    fn load(path: &Path) -> Result<Manifest> {
+        match fs::read_to_string(path.join(MANIFEST)) {
+            Ok(manifest) => serde_json::from_str(&manifest)
+                .with_context(|| format!("invalid manifest for `{}`", path.display())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Manifest::scan(path),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn scan(path: &Path) -> Result<Manifest> {
+        let expr = path.join("[0-9]".repeat(20));
+        let expr = expr.to_str().unwrap();
+
+        let mut segments = Vec::new();
+        for segment in glob(&expr)?.map(|x| x.unwrap()) {
+            let name = segment.file_name().unwrap().to_str().unwrap();
+            segments.push(SegmentEntry {
+                offset: name.parse::<u64>().unwrap(),
+                path: name.to_string(),
+                size: segment.metadata()?.len(),
+                sealed: true,
+                checksum: None,
+            });
+        }
+        if let Some(last) = segments.last_mut() {
+            last.sealed = false;
+        }
+
+        Ok(Manifest {
+            version: 1,
+            layout: Layout::Flat,
+            segments,
+        })
+    }
+
+    /// Atomically replaces the log's manifest with this one.
+    fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.join(format!("{}.tmp", MANIFEST));
+        fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&tmp, path.join(MANIFEST))?;
+        Ok(())
+    }
+
+    /// Opens the active segment for appending, first sealing it and starting a new
+    /// one at `offset` if it doesn't already start there.
+    fn open_segment(&mut self, path: &Path, offset: u64) -> Result<fs::File> {
+        match self.segments.last() {
+            Some(last) if !last.sealed && last.offset == offset => (),
+            _ => {
+                if let Some(last) = self.segments.last_mut() {
+                    if !last.sealed {
+                        last.seal(path)?;
+                    }
+                }
+
+                let segment = self.layout.segment(offset);
+                let segment_path = path.join(&segment);
+                fs::create_dir_all(segment_path.parent().unwrap())?;
+                fs::OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(&segment_path)?;
+
+                self.segments.push(SegmentEntry {
+                    offset,
+                    path: segment.clone(),
+                    size: 0,
+                    sealed: false,
+                    checksum: None,
+                });
+                self.save(path)?;
+
+                // a convenience for humans: the manifest is authoritative, so this
+                // is skipped on filesystems without symlinks
+                let link = path.join("current");
+                let _ = fs::remove_file(&link);
+                let _ = symlink(&segment, &link);
+            }
+        }
+
+        let active = path.join(&self.segments.last().unwrap().path);
+        Ok(fs::OpenOptions::new().append(true).open(active)?)
+    }
+}
+
+/// Lists the segments of the log at `path`, in order, as (offset, path) pairs.
+fn list_segments(path: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    Ok(Manifest::load(path)?
+        .segments
+        .iter()
+        .map(|entry| (entry.offset, path.join(&entry.path)))
+        .collect())
+}
+
+/// The segments of a log, as listed by `list_segments`, kept between lookups.
+/// They're only reloaded once the manifest, or the directory of a log without
+/// one, has changed, so readers can refresh them on every poll.
+struct Segments {
+    path: PathBuf,
+    /// the inode, size and modification time the segments were loaded at
+    stamp: Option<(u64, u64, time::SystemTime)>,
+    list: Vec<(u64, PathBuf)>,
+}
+
+impl Segments {
+    fn load(path: &Path) -> Result<Segments> {
+        let mut segments = Segments {
+            path: path.to_path_buf(),
+            stamp: None,
+            list: Vec::new(),
+        };
+        segments.refresh()?;
+        Ok(segments)
+    }
+
+    /// Reloads the segments, if they may have changed since they were loaded.
+    fn refresh(&mut self) -> Result<()> {
+        let stamp = fs::metadata(self.path.join(MANIFEST))
+            .or_else(|_| fs::metadata(&self.path))
+            .and_then(|m| Ok((m.ino(), m.len(), m.modified()?)))
+            .ok();
+        if stamp.is_none() || stamp != self.stamp {
+            self.list = list_segments(&self.path)?;
+            self.stamp = stamp;
+        }
+        Ok(())
+    }
+
+    /// Returns the offset of the first line of the log.
+    fn start(&self) -> u64 {
+        self.list.first().map_or(0, |(offset, _)| *offset)
+    }
+
+    /// Returns the path of the segment starting at `offset`, if there is one.
+    fn get(&self, offset: u64) -> Option<&Path> {
+        let i = self.list.binary_search_by_key(&offset, |(o, _)| *o).ok()?;
+        Some(&self.list[i].1)
+    }
+
+    /// Returns the segment holding the byte at `cursor`, if there is one.
+    fn find(&self, cursor: u64) -> Result<Option<(u64, PathBuf)>> {
+        let i = self.list.partition_point(|(offset, _)| *offset <= cursor);
+        let (offset, segment) = match i.checked_sub(1) {
+            Some(i) => &self.list[i],
+            None => return Ok(None),
+        };
+        if cursor < offset + segment.metadata()?.len() {
+            return Ok(Some((*offset, segment.clone())));
+        }
+        Ok(None)
+    }
+}
+
+/// Sets the layout of a new log. Existing logs keep the layout they were created
+/// with.
+fn set_layout(path: &Path, layout: Layout) -> Result<()> {
+    let mut manifest = Manifest::load(path)?;
+    if !manifest.segments.is_empty() {
+        anyhow::ensure!(
+            manifest.layout == layout,
+            "`{}` already uses the {:?} layout",
+            path.display(),
+            manifest.layout
+        );
+        return Ok(());
+    }
+    fs::create_dir_all(path)
+        .with_context(|| format!("could not create directory `{}`", path.display()))?;
+    manifest.layout = layout;
+    manifest.save(path)
+}
+
+/// Returns the segment holding the byte at `cursor`, if there is one.
+fn find_segment(path: &Path, cursor: u64) -> Result<Option<(u64, PathBuf)>> {
+    Segments::load(path)?.find(cursor)
+}
+
+/// Returns the offset of the first line of the log. Logs start at 0, unless they
+/// were created by importing an archive of a later range of another log.
+fn log_start(path: &Path) -> Result<u64> {
+    Ok(list_segments(path)?
+        .first()
+        .map_or(0, |(offset, _)| *offset))
+}
+
+/// Returns the offset immediately following the last line of the log.
+fn log_end(path: &Path) -> Result<u64> {
+    match list_segments(path)?.pop() {
+        Some((offset, segment)) => Ok(offset + segment.metadata()?.len()),
+        None => Ok(0),
+    }
+}
+
 fn run_write<R: Read>(r: R, path: &Path, max_segment: u64) -> Result<()> {
     fs::create_dir(path)
         .or_else(|e| match e.kind() {
@@ -113,48 +640,35 @@ fn run_write<R: Read>(r: R, path: &Path, max_segment: u64) -> Result<()> {
         })
         .with_context(|| format!("could not create directory `{}`", path.display()))?;
 
-    let mut expected = 0;
-
-    let expr = path.join("[0-9]".repeat(20));
-    let expr = expr.to_str().unwrap();
+    let mut manifest = Manifest::load(path)?;
+    let mut expected = manifest.segments.first().map_or(0, |entry| entry.offset);
 
-    for segment in glob(&expr)?.map(|x| x.unwrap()) {
-        let offset = segment
-            .file_name()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .parse::<u64>()
-            .unwrap();
+    for entry in manifest.segments.iter_mut() {
+        let segment = path.join(&entry.path);
         assert!(
-            offset == expected,
+            entry.offset == expected,
             "expected: {:020}, have: {}",
             expected,
             segment.display(),
         );
-        expected += segment.metadata().unwrap().len();
-    }
-
-    fn open_current(path: &Path, expected: u64) -> Result<fs::File> {
-        let current = format!("{:020}", expected);
-        let fh = fs::OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(path.join(&current))?;
-
-        let link = path.join("current");
-        symlink(&current, &link).or_else(|e| match e.kind() {
-            io::ErrorKind::AlreadyExists => {
-                let _ = fs::remove_file(&link);
-                return symlink(&current, &link);
+        let size = segment.metadata().unwrap().len();
+        if entry.sealed {
+            assert!(
+                entry.size == size,
+                "sealed: {}, expected size: {}, have: {}",
+                segment.display(),
+                entry.size,
+                size,
+            );
+            // segments sealed before manifests were introduced
+            if entry.checksum.is_none() {
+                entry.seal(path)?;
             }
-            _ => Err(e),
-        })?;
-
-        Ok(fh)
+        }
+        expected += size;
     }
 
-    let mut fh = open_current(path, expected)?;
+    let mut fh = manifest.open_segment(path, expected)?;
     let mut fh_size = fh.metadata()?.len();
 
     let buf = BufReader::new(r);
@@ -172,7 +686,7 @@ fn run_write<R: Read>(r: R, path: &Path, max_segment: u64) -> Result<()> {
 
         if fh_size + new_bytes > max_segment {
             expected += fh_size;
-            fh = open_current(path, expected)?;
+            fh = manifest.open_segment(path, expected)?;
             fh_size = 0;
         }
 
@@ -180,20 +694,64 @@ fn run_write<R: Read>(r: R, path: &Path, max_segment: u64) -> Result<()> {
         fh_size += new_bytes;
     }
 
+    manifest.segments.last_mut().unwrap().size = fh_size;
+    manifest.save(path)?;
+
     Ok(())
 }
 
+/// Bounds for batched reads. A batch is written once it holds `lines` lines or
+/// `bytes` bytes of log data, whichever comes first. Once the reader has caught up
+/// with the end of the log, a partial batch is written after `wait` has elapsed
+/// since its first line was read.
+struct Batch {
+    lines: Option<usize>,
+    bytes: Option<u64>,
+    wait: time::Duration,
+}
+
+/// Writes `lines` as a single JSON array and, when tracking, the cursor of the
+/// batch: the offset immediately following its last line.
+fn write_batch<W: Write, T: Write>(
+    w: &mut W,
+    track: &mut Option<&mut T>,
+    lines: &mut Vec<String>,
+    offset: u64,
+) {
+    writeln!(w, "{}", serde_json::to_string(&lines).unwrap()).unwrap();
+    if let Some(ref mut t) = track {
+        writeln!(t, "{}", offset).unwrap();
+    }
+    lines.clear();
+}
+
+/// Reads the log from `cursor`, writing each line, or batch of lines, to `w`. With
+/// `exec`, each line is instead handed to a run of the command, and the read stops
+/// at the first it fails to handle, returning the command's exit code.
 fn run_read<W: Write, T: Write>(
     w: &mut W,
     path: &Path,
     cursor: u64,
     follow: bool,
+    batch: Option<Batch>,
+    exec: Option<&[String]>,
     mut track: Option<&mut T>,
-) -> Result<()> {
-    let mut offset = 0;
+) -> Result<Option<i32>> {
+    let mut segments = Segments::load(path)?;
+    let mut offset = segments.start();
+
+    let mut pending: Vec<String> = Vec::new();
+    let mut pending_bytes = 0;
+    let mut pending_since = time::Instant::now();
 
     loop {
-        let segment = path.join(format!("{:020}", offset));
+        let segment =
+            segments
+                .get(offset)
+                .map(Path::to_path_buf)
+                .with_context(|| {
+                    format!("`{}` has no segment at {}", path.display(), offset)
+                })?;
         let segment_size = segment.metadata().unwrap().len();
 
         // fast forward until we find the segment our cursor is in
@@ -214,21 +772,71 @@ fn run_read<W: Write, T: Write>(
             match lines.next() {
                 Some(line) => {
                     let line = line.unwrap();
-                    writeln!(w, "{}", &line).unwrap();
-                    offset += line.len() as u64 + 1;
-                    if let Some(ref mut t) = track {
-                        writeln!(t, "{}", offset).unwrap();
+                    let new_bytes = line.len() as u64 + 1;
+
+                    let batch = match batch {
+                        Some(ref batch) => batch,
+                        None => {
+                            match exec {
+                                Some(command) => {
+                                    let status = exec_line(command, &line)?;
+                                    if !status.success() {
+                                        return Ok(Some(status.code().unwrap_or(1)));
+                                    }
+                                }
+                                None => writeln!(w, "{}", &line).unwrap(),
+                            }
+                            offset += new_bytes;
+                            if let Some(ref mut t) = track {
+                                writeln!(t, "{}", offset).unwrap();
+                            }
+                            continue;
+                        }
+                    };
+
+                    // flush first if this line would take the batch over its
+                    // byte bound
+                    if !pending.is_empty()
+                        && matches!(batch.bytes, Some(max) if pending_bytes + new_bytes > max)
+                    {
+                        write_batch(w, &mut track, &mut pending, offset);
+                        pending_bytes = 0;
+                    }
+
+                    if pending.is_empty() {
+                        pending_since = time::Instant::now();
+                    }
+                    pending.push(line);
+                    pending_bytes += new_bytes;
+                    offset += new_bytes;
+
+                    if matches!(batch.lines, Some(max) if pending.len() >= max)
+                        || matches!(batch.bytes, Some(max) if pending_bytes >= max)
+                    {
+                        write_batch(w, &mut track, &mut pending, offset);
+                        pending_bytes = 0;
                     }
                 }
                 None => {
                     // is the next segment available?
-                    let next_segment = path.join(format!("{:020}", offset));
-                    if next_segment.is_file() {
+                    segments.refresh()?;
+                    if segments.get(offset).is_some() {
                         break;
                     }
 
+                    // we've caught up: write out any partial batch that has waited
+                    // long enough
+                    if let Some(ref batch) = batch {
+                        if !pending.is_empty()
+                            && (!follow || pending_since.elapsed() >= batch.wait)
+                        {
+                            write_batch(w, &mut track, &mut pending, offset);
+                            pending_bytes = 0;
+                        }
+                    }
+
                     if !follow {
-                        return Ok(());
+                        return Ok(None);
                     }
 
                     // poll the current segment for new data
@@ -240,13 +848,525 @@ fn run_read<W: Write, T: Write>(
     }
 }
 
+/// A consumer group member's claim on a single segment of the log. Leases are
+/// files in the group's directory, created exclusively, whose modification time is
+/// refreshed while the member makes progress. A lease that hasn't been renewed
+/// within the lease timeout can be taken over by another member.
+struct Lease {
+    path: PathBuf,
+    token: String,
+    timeout: time::Duration,
+    renewed: time::Instant,
+}
+
+impl Lease {
+    fn acquire(
+        dir: &Path,
+        offset: u64,
+        timeout: time::Duration,
+    ) -> Result<Option<Lease>> {
+        let path = dir.join(format!("{:020}.lease", offset));
+        let token = Uuid::new_v4().to_string();
+
+        loop {
+            match fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(mut fh) => {
+                    fh.write_all(token.as_bytes())?;
+                    return Ok(Some(Lease {
+                        path,
+                        token,
+                        timeout,
+                        renewed: time::Instant::now(),
+                    }));
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    let modified = match path.metadata().and_then(|m| m.modified()) {
+                        Ok(modified) => modified,
+                        // released while we were looking at it
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                        Err(e) => return Err(e.into()),
+                    };
+                    if modified.elapsed().unwrap_or_default() < timeout {
+                        return Ok(None);
+                    }
+                    // the owner has stopped renewing the lease: take it over by
+                    // moving it aside, which only one member can do
+                    let stale = dir.join(format!("{:020}.lease.{}", offset, token));
+                    match fs::rename(&path, &stale) {
+                        Ok(()) => {}
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                        Err(e) => return Err(e.into()),
+                    }
+                    // the lease may have been renewed, or released and acquired
+                    // afresh, since we looked at it: if so, put it back
+                    let moved = stale.metadata().and_then(|m| m.modified()).ok();
+                    if moved != Some(modified) {
+                        let _ = fs::hard_link(&stale, &path);
+                        let _ = fs::remove_file(&stale);
+                        return Ok(None);
+                    }
+                    fs::remove_file(&stale)?;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn is_held(&self) -> bool {
+        matches!(fs::read_to_string(&self.path), Ok(token) if token == self.token)
+    }
+
+    /// Refreshes the lease, if it's due. Returns false if the lease has been taken
+    /// over by another member.
+    fn renew(&mut self) -> Result<bool> {
+        if self.renewed.elapsed() < self.timeout / 3 {
+            return Ok(true);
+        }
+        if !self.is_held() {
+            return Ok(false);
+        }
+        // only the modification time is refreshed, so a lease taken over in the
+        // meantime is neither recreated nor overwritten
+        match fs::OpenOptions::new().write(true).open(&self.path) {
+            Ok(fh) => fh.set_modified(time::SystemTime::now())?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        }
+        self.renewed = time::Instant::now();
+        Ok(true)
+    }
+
+    fn release(self) {
+        if self.is_held() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Returns the group's committed cursor for the segment at `offset`: the offset of
+/// the next line in the segment to deliver.
+fn read_commit(dir: &Path, offset: u64) -> Result<u64> {
+    match fs::read_to_string(dir.join(format!("{:020}", offset))) {
+        Ok(cursor) => Ok(cursor.trim().parse()?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(offset),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_commit(dir: &Path, offset: u64, cursor: u64) -> Result<()> {
+    let commit = dir.join(format!("{:020}", offset));
+    let tmp = dir.join(format!("{:020}.tmp", offset));
+    fs::write(&tmp, cursor.to_string())?;
+    fs::rename(&tmp, &commit)?;
+    Ok(())
+}
+
+/// Runs `command` with `line` on its STDIN, returning its exit status.
+fn exec_line(command: &[String], line: &str) -> Result<process::ExitStatus> {
+    let mut child = process::Command::new(&command[0])
+        .args(&command[1..])
+        .stdin(process::Stdio::piped())
+        .spawn()
+        .with_context(|| format!("could not execute `{}`", command[0]))?;
+    let mut stdin = child.stdin.take().unwrap();
+    // the command may exit without reading its input
+    let _ = writeln!(stdin, "{}", line);
+    drop(stdin);
+    Ok(child.wait()?)
+}
+
+/// Reads as a member of a consumer group. Each line is written to `w`, or, with
+/// `exec`, handed to a run of the command, and committed once that's done. If the
+/// command fails, the read stops, leaving the line to be redelivered, and the
+/// command's exit code is returned.
+fn run_read_group<W: Write, T: Write>(
+    w: &mut W,
+    path: &Path,
+    group: &str,
+    timeout: time::Duration,
+    follow: bool,
+    exec: Option<&[String]>,
+    mut track: Option<&mut T>,
+) -> Result<Option<i32>> {
+    let dir = path.join("groups").join(group);
+    fs::create_dir_all(&dir)
+        .with_context(|| format!("could not create directory `{}`", dir.display()))?;
+
+    let mut segments = Segments::load(path)?;
+    loop {
+        segments.refresh()?;
+        let list = segments.list.clone();
+        let mut claimed = false;
+
+        for (i, (offset, segment)) in list.iter().enumerate() {
+            let sealed = i + 1 < list.len();
+            let end = offset + segment.metadata()?.len();
+
+            // skip segments which have been fully delivered, and, unless we're
+            // following, an active segment with nothing new to deliver
+            if (sealed || !follow) && read_commit(&dir, *offset)? >= end {
+                continue;
+            }
+
+            let mut lease = match Lease::acquire(&dir, *offset, timeout)? {
+                Some(lease) => lease,
+                None => continue,
+            };
+            claimed = true;
+
+            // another member may have made progress since we last looked
+            let mut cursor = read_commit(&dir, *offset)?;
+
+            let mut fh = fs::OpenOptions::new().read(true).open(segment)?;
+            fh.seek(io::SeekFrom::Start(cursor - offset))?;
+
+            let mut lines = BufReader::new(&fh).lines();
+            loop {
+                if !lease.renew()? {
+                    break;
+                }
+
+                match lines.next() {
+                    Some(line) => {
+                        let line = line?;
+                        match exec {
+                            Some(command) => {
+                                let status = exec_line(command, &line)?;
+                                if !status.success() {
+                                    lease.release();
+                                    return Ok(Some(status.code().unwrap_or(1)));
+                                }
+                            }
+                            None => {
+                                writeln!(w, "{}", &line)?;
+                                w.flush()?;
+                            }
+                        }
+                        cursor += line.len() as u64 + 1;
+                        write_commit(&dir, *offset, cursor)?;
+                        if let Some(ref mut t) = track {
+                            writeln!(t, "{}", cursor).unwrap();
+                        }
+                    }
+                    None => {
+                        // the segment is complete once the next segment exists
+                        if !follow {
+                            break;
+                        }
+                        segments.refresh()?;
+                        if segments.get(cursor).is_some() {
+                            break;
+                        }
+                        let m = time::Duration::from_millis(10);
+                        thread::sleep(m);
+                    }
+                }
+            }
+
+            lease.release();
+        }
+
+        if !claimed {
+            if !follow {
+                return Ok(None);
+            }
+            // everything is either delivered or leased by another member
+            let m = time::Duration::from_millis(100);
+            thread::sleep(m);
+        }
+    }
+}
+
+/// Iterates over the lines of a log, starting from a cursor, yielding each line
+/// along with the cursor that follows it. Stops at the current end of the log.
+struct Lines {
+    path: PathBuf,
+    segments: Option<Segments>,
+    offset: u64,
+    lines: Option<io::Lines<BufReader<fs::File>>>,
+}
+
+impl Lines {
+    fn new(path: &Path, cursor: u64) -> Lines {
+        Lines {
+            path: path.to_path_buf(),
+            segments: None,
+            offset: cursor,
+            lines: None,
+        }
+    }
+
+    fn read_line(&mut self) -> Result<Option<(String, u64)>> {
+        loop {
+            if self.lines.is_none() {
+                let segments = match &mut self.segments {
+                    Some(segments) => {
+                        segments.refresh()?;
+                        segments
+                    }
+                    None => self.segments.insert(Segments::load(&self.path)?),
+                };
+                self.offset = self.offset.max(segments.start());
+                let (offset, segment) = match segments.find(self.offset)? {
+                    Some(segment) => segment,
+                    None => return Ok(None),
+                };
+
+                let mut fh = fs::OpenOptions::new().read(true).open(&segment)?;
+                fh.seek(io::SeekFrom::Start(self.offset - offset))?;
+                self.lines = Some(BufReader::new(fh).lines());
+            }
+
+            match self.lines.as_mut().unwrap().next() {
+                Some(line) => {
+                    let line = line?;
+                    self.offset += line.len() as u64 + 1;
+                    return Ok(Some((line, self.offset)));
+                }
+                // move on to the next segment, if there is one
+                None => self.lines = None,
+            }
+        }
+    }
+}
+
+impl Iterator for Lines {
+    type Item = Result<(String, u64)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_line().transpose()
+    }
+}
+
+/// Returns the timestamp, in seconds since the epoch, held in `field` of a JSON
+/// line. Dots in `field` address nested fields.
+fn timestamp(line: &str, field: &str) -> Option<f64> {
+    let value: serde_json::Value = serde_json::from_str(line).ok()?;
+    match value.pointer(&format!("/{}", field.replace('.', "/")))? {
+        serde_json::Value::Number(n) => n.as_f64(),
+        serde_json::Value::String(s) => {
+            let ts = chrono::DateTime::parse_from_rfc3339(s).ok()?;
+            Some(ts.timestamp() as f64 + ts.timestamp_subsec_nanos() as f64 / 1e9)
+        }
+        _ => None,
+    }
+}
+
+fn run_cat<W: Write, T: Write>(
+    w: &mut W,
+    paths: &[PathBuf],
+    mut cursors: Vec<u64>,
+    field: &str,
+    mut track: Option<&mut T>,
+) -> Result<()> {
+    let mut logs: Vec<Lines> = paths
+        .iter()
+        .zip(&cursors)
+        .map(|(path, cursor)| Lines::new(path, *cursor))
+        .collect();
+
+    // the next line from each log, along with its timestamp and following cursor
+    let mut heads: Vec<Option<(f64, String, u64)>> = Vec::new();
+    for log in logs.iter_mut() {
+        heads.push(log.next().transpose()?.map(|(line, cursor)| {
+            let ts = timestamp(&line, field).unwrap_or(f64::NEG_INFINITY);
+            (ts, line, cursor)
+        }));
+    }
+
+    loop {
+        // the earliest head, favoring the first log given on ties
+        let mut next: Option<usize> = None;
+        for (i, head) in heads.iter().enumerate() {
+            if let Some((ts, _, _)) = head {
+                match next {
+                    Some(j) if heads[j].as_ref().unwrap().0 <= *ts => (),
+                    _ => next = Some(i),
+                }
+            }
+        }
+        let i = match next {
+            Some(i) => i,
+            None => return Ok(()),
+        };
+
+        let (ts, line, cursor) = heads[i].take().unwrap();
+        writeln!(w, "{}", &line)?;
+        cursors[i] = cursor;
+        if let Some(ref mut t) = track {
+            let cursors: Vec<String> = cursors.iter().map(|x| x.to_string()).collect();
+            writeln!(t, "{}", cursors.join(",")).unwrap();
+        }
+
+        heads[i] = logs[i].next().transpose()?.map(|(line, cursor)| {
+            // lines without a timestamp stay with the line before them
+            let ts = timestamp(&line, field).unwrap_or(ts);
+            (ts, line, cursor)
+        });
+    }
+}
+
+/// Metadata written as the first line of an exported archive. The line is
+/// followed by the `to - from` bytes of the log in that range.
+#[derive(Serialize, Deserialize)]
+struct ArchiveHeader {
+    format: String,
+    version: u32,
+    source: String,
+    from: u64,
+    to: u64,
+    exported_at: String,
+}
+
+const ARCHIVE_FORMAT: &str = "x-log-archive";
+
+fn run_export<W: Write>(
+    w: &mut W,
+    path: &Path,
+    from: u64,
+    to: Option<u64>,
+) -> Result<()> {
+    let to = match to {
+        Some(to) => to,
+        None => log_end(path)?,
+    };
+    let start = log_start(path)?;
+    let from = from.max(start);
+    anyhow::ensure!(from <= to, "--from {} is after --to {}", from, to);
+
+    // the range must start at a message boundary
+    if from > start {
+        let (offset, segment) = find_segment(path, from - 1)?
+            .with_context(|| format!("--from {} is past the end of the log", from))?;
+        let mut fh = fs::OpenOptions::new().read(true).open(&segment)?;
+        fh.seek(io::SeekFrom::Start(from - 1 - offset))?;
+        let mut byte = [0; 1];
+        fh.read_exact(&mut byte)?;
+        anyhow::ensure!(byte[0] == b'\n', "--from {} isn't at a line boundary", from);
+    }
+
+    let header = ArchiveHeader {
+        format: ARCHIVE_FORMAT.to_string(),
+        version: 1,
+        source: path.display().to_string(),
+        from,
+        to,
+        exported_at: chrono::Utc::now().to_rfc3339(),
+    };
+    writeln!(w, "{}", serde_json::to_string(&header)?)?;
+
+    let mut cursor = from;
+    for line in Lines::new(path, from) {
+        if cursor == to {
+            break;
+        }
+        let (line, next) = line?;
+        anyhow::ensure!(next <= to, "--to {} isn't at a line boundary", to);
+        writeln!(w, "{}", &line)?;
+        cursor = next;
+    }
+    anyhow::ensure!(cursor == to, "--to {} is past the end of the log", to);
+
+    Ok(())
+}
+
+fn run_import<R: BufRead>(
+    mut r: R,
+    path: &Path,
+    max_segment: u64,
+    force: bool,
+) -> Result<()> {
+    loop {
+        let mut header = String::new();
+        if r.read_line(&mut header)? == 0 {
+            return Ok(());
+        }
+        let header: ArchiveHeader =
+            serde_json::from_str(&header).context("unable to parse archive header")?;
+        anyhow::ensure!(
+            header.format == ARCHIVE_FORMAT && header.version == 1,
+            "unsupported archive format: {} v{}",
+            header.format,
+            header.version
+        );
+        anyhow::ensure!(
+            header.from <= header.to,
+            "archive of `{}` starts at {}, after its end at {}",
+            header.source,
+            header.from,
+            header.to
+        );
+
+        // archives must be contiguous with what's already in the log. An empty log
+        // takes on the archive's offsets, so further ranges can follow it.
+        let empty = list_segments(path)?.is_empty();
+        let end = if empty { header.from } else { log_end(path)? };
+        anyhow::ensure!(
+            force || end == header.from,
+            "archive of `{}` starts at {}, but the log ends at {}",
+            header.source,
+            header.from,
+            end
+        );
+
+        // the range is staged in the log's directory before any of it's appended,
+        // so a truncated archive leaves the log as it was
+        let bytes = header.to - header.from;
+        let created = !path.exists();
+        fs::create_dir_all(path).with_context(|| {
+            format!("could not create directory `{}`", path.display())
+        })?;
+        let staged = path.join("import.tmp");
+        let copied = fs::File::create(&staged)
+            .and_then(|mut fh| io::copy(&mut (&mut r).take(bytes), &mut fh));
+        if !matches!(copied, Ok(copied) if copied == bytes) {
+            let _ = fs::remove_file(&staged);
+            if created {
+                let _ = fs::remove_dir(path);
+            }
+            anyhow::bail!(
+                "archive of `{}` is truncated: expected {} bytes, found {}",
+                header.source,
+                bytes,
+                copied?
+            );
+        }
+
+        if empty {
+            Manifest::load(path)?.open_segment(path, header.from)?;
+        }
+        let written = fs::File::open(&staged)
+            .map_err(anyhow::Error::from)
+            .and_then(|fh| run_write(fh, path, max_segment));
+        let _ = fs::remove_file(&staged);
+        written?;
+
+        let imported = log_end(path)? - end;
+        anyhow::ensure!(
+            imported == bytes,
+            "archive of `{}` is truncated: expected {} bytes, imported {}",
+            header.source,
+            bytes,
+            imported
+        );
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{run_read, run_write};
+    use super::{
+        run_cat, run_export, run_import, run_read, run_read_group, run_write,
+        set_layout, Batch, Layout, Manifest, Segments,
+    };
 
     use std::fs;
     use std::io::{self, Read, Write};
     use std::str::from_utf8;
+    use std::time;
 
     use anyhow::Result;
     use tempfile::tempdir;
@@ -306,7 +1426,15 @@ mod tests {
 
         // read all
         let mut stdout = io::Cursor::new(Vec::new());
-        run_read(&mut stdout, path, 0, false, None::<&mut fs::File>)?;
+        run_read(
+            &mut stdout,
+            path,
+            0,
+            false,
+            None,
+            None,
+            None::<&mut fs::File>,
+        )?;
         assert_eq!(from_utf8(stdout.get_ref())?, segment1);
 
         // read from cursor
@@ -316,6 +1444,8 @@ mod tests {
             path,
             "one\n".len() as u64,
             false,
+            None,
+            None,
             None::<&mut fs::File>,
         )?;
         assert_eq!(from_utf8(stdout.get_ref())?, "two\nthree\nfour\n");
@@ -326,7 +1456,15 @@ mod tests {
 
         // read all
         let mut stdout = io::Cursor::new(Vec::new());
-        run_read(&mut stdout, path, 0, false, None::<&mut fs::File>)?;
+        run_read(
+            &mut stdout,
+            path,
+            0,
+            false,
+            None,
+            None,
+            None::<&mut fs::File>,
+        )?;
         assert_eq!(
             from_utf8(stdout.get_ref())?,
             [segment1, segment2, segment3].join("")
@@ -339,6 +1477,8 @@ mod tests {
             path,
             (segment1.len() + "one-2\n".len()) as u64,
             false,
+            None,
+            None,
             None::<&mut fs::File>,
         )?;
         assert_eq!(
@@ -353,6 +1493,8 @@ mod tests {
             path,
             (segment1.len() + segment2.len() + "one-3\n".len()) as u64,
             false,
+            None,
+            None,
             None::<&mut fs::File>,
         )?;
         assert_eq!(from_utf8(stdout.get_ref())?, "two-3\nthree-3\nfour-3\n");
@@ -364,10 +1506,404 @@ mod tests {
             path,
             (segment1.len() + segment2.len() + segment3.len()) as u64,
             false,
+            None,
+            None,
             None::<&mut fs::File>,
         )?;
         assert_eq!(from_utf8(stdout.get_ref())?, "");
 
+        // exec hands each line to a command, stopping at the first it fails on,
+        // with the cursor of each line handled tracked
+        let out = tempdir()?;
+        let handled = out.path().join("handled");
+        let script = format!(
+            "read line; [ $line != two-3 ] && echo $line >> {}",
+            handled.display()
+        );
+        let command = ["sh".to_string(), "-c".to_string(), script];
+        let mut track = io::Cursor::new(Vec::new());
+        let cursor = (segment1.len() + segment2.len()) as u64;
+        let code = run_read(
+            &mut io::sink(),
+            path,
+            cursor,
+            false,
+            None,
+            Some(&command),
+            Some(&mut track),
+        )?;
+        assert_eq!(code, Some(1));
+        assert_eq!(fs::read_to_string(&handled)?, "one-3\n");
+        assert_eq!(from_utf8(track.get_ref())?, format!("{}\n", cursor + 6));
+
+        Ok(())
+    }
+
+    #[test]
+    fn log_read_batch() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path();
+
+        run_write(io::Cursor::new("one\ntwo\nthree\n"), path, 1024 * 1024)?;
+        run_write(io::Cursor::new("four\nfive\n"), path, 1024 * 1024)?;
+
+        // batches span segments, and the final partial batch is flushed
+        let mut stdout = io::Cursor::new(Vec::new());
+        let mut track = io::Cursor::new(Vec::new());
+        let batch = Batch {
+            lines: Some(2),
+            bytes: None,
+            wait: time::Duration::from_millis(0),
+        };
+        run_read(
+            &mut stdout,
+            path,
+            0,
+            false,
+            Some(batch),
+            None,
+            Some(&mut track),
+        )?;
+        assert_eq!(
+            from_utf8(stdout.get_ref())?,
+            "[\"one\",\"two\"]\n[\"three\",\"four\"]\n[\"five\"]\n"
+        );
+        assert_eq!(from_utf8(track.get_ref())?, "8\n19\n24\n");
+
+        // a batch never exceeds its byte bound, unless a single line does
+        let mut stdout = io::Cursor::new(Vec::new());
+        let mut track = io::Cursor::new(Vec::new());
+        let batch = Batch {
+            lines: None,
+            bytes: Some(9),
+            wait: time::Duration::from_millis(0),
+        };
+        run_read(
+            &mut stdout,
+            path,
+            4,
+            false,
+            Some(batch),
+            None,
+            Some(&mut track),
+        )?;
+        assert_eq!(
+            from_utf8(stdout.get_ref())?,
+            "[\"two\"]\n[\"three\"]\n[\"four\"]\n[\"five\"]\n"
+        );
+        assert_eq!(from_utf8(track.get_ref())?, "8\n14\n19\n24\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn log_read_group() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path();
+
+        // one segment per write
+        run_write(io::Cursor::new("one\ntwo\n"), path, 8)?;
+        run_write(io::Cursor::new("three\n"), path, 8)?;
+        run_write(io::Cursor::new("four\n"), path, 8)?;
+
+        let timeout = time::Duration::from_secs(60);
+        let read = |timeout| -> Result<String> {
+            let mut stdout = io::Cursor::new(Vec::new());
+            run_read_group(
+                &mut stdout,
+                path,
+                "workers",
+                timeout,
+                false,
+                None,
+                None::<&mut fs::File>,
+            )?;
+            Ok(from_utf8(stdout.get_ref())?.to_string())
+        };
+
+        // a live lease held by another member on the first segment
+        let leases = path.join("groups/workers");
+        fs::create_dir_all(&leases)?;
+        fs::write(leases.join(format!("{:020}.lease", 0)), "crashed")?;
+
+        assert_eq!(read(timeout)?, "three\nfour\n");
+        assert_eq!(read(timeout)?, "");
+
+        // once the lease expires, the segment is redelivered to another member
+        assert_eq!(read(time::Duration::from_secs(0))?, "one\ntwo\n");
+        assert_eq!(read(timeout)?, "");
+
+        // new lines in the active segment are picked up by the next read
+        run_write(io::Cursor::new("five\n"), path, 16)?;
+        assert_eq!(read(timeout)?, "five\n");
+
+        // a line the command fails to handle isn't committed, so it's redelivered
+        run_write(io::Cursor::new("six\nseven\n"), path, 16)?;
+        let handled = dir.path().join("handled");
+        let script = format!(
+            "read line; [ $line != seven ] && echo $line >> {}",
+            handled.display()
+        );
+        let command = ["sh".to_string(), "-c".to_string(), script];
+        let mut stdout = io::Cursor::new(Vec::new());
+        let code = run_read_group(
+            &mut stdout,
+            path,
+            "workers",
+            timeout,
+            false,
+            Some(&command),
+            None::<&mut fs::File>,
+        )?;
+        assert_eq!(code, Some(1));
+        assert_eq!(fs::read_to_string(&handled)?, "six\n");
+        assert_eq!(read(timeout)?, "seven\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn log_cat() -> Result<()> {
+        let dir = tempdir()?;
+        let a = dir.path().join("a");
+        let b = dir.path().join("b");
+
+        let log_a = [
+            r#"{"timestamp": 1, "line": "a1"}"#,
+            r#"{"timestamp": 3, "line": "a3"}"#,
+            r#"{"line": "a3, no timestamp"}"#,
+            r#"{"timestamp": "1970-01-01T00:00:05Z", "line": "a5"}"#,
+        ];
+        let log_b = [
+            r#"{"timestamp": 2, "line": "b2"}"#,
+            r#"{"timestamp": 3, "line": "b3"}"#,
+            r#"{"timestamp": "1970-01-01T01:00:04+01:00", "line": "b4"}"#,
+        ];
+        // spread the first log over a couple of segments
+        run_write(io::Cursor::new(log_a[..2].join("\n")), &a, 1024)?;
+        run_write(io::Cursor::new(log_a[2..].join("\n")), &a, 1024)?;
+        run_write(io::Cursor::new(log_b.join("\n")), &b, 1024)?;
+
+        let paths = [a, b];
+        let merged = [
+            log_a[0], log_b[0], log_a[1], log_a[2], log_b[1], log_b[2], log_a[3],
+        ];
+
+        let mut stdout = io::Cursor::new(Vec::new());
+        let mut track = io::Cursor::new(Vec::new());
+        run_cat(
+            &mut stdout,
+            &paths,
+            vec![0, 0],
+            "timestamp",
+            Some(&mut track),
+        )?;
+        assert_eq!(from_utf8(stdout.get_ref())?, merged.join("\n") + "\n");
+
+        // resume from the composite cursor of the fourth line
+        let track = from_utf8(track.get_ref())?;
+        let cursor: Vec<u64> = track
+            .lines()
+            .nth(3)
+            .unwrap()
+            .split(',')
+            .map(|x| x.parse().unwrap())
+            .collect();
+        let mut stdout = io::Cursor::new(Vec::new());
+        run_cat(
+            &mut stdout,
+            &paths,
+            cursor,
+            "timestamp",
+            None::<&mut fs::File>,
+        )?;
+        assert_eq!(from_utf8(stdout.get_ref())?, merged[4..].join("\n") + "\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn log_export_import() -> Result<()> {
+        let dir = tempdir()?;
+        let source = dir.path().join("source");
+        let target = dir.path().join("target");
+
+        run_write(io::Cursor::new("one\ntwo\n"), &source, 8)?;
+        run_write(io::Cursor::new("three\nfour\n"), &source, 8)?;
+
+        let export = |from, to| -> Result<Vec<u8>> {
+            let mut archive = Vec::new();
+            run_export(&mut archive, &source, from, to)?;
+            Ok(archive)
+        };
+        let read = |path| -> Result<String> {
+            let mut stdout = io::Cursor::new(Vec::new());
+            run_read(
+                &mut stdout,
+                path,
+                0,
+                false,
+                None,
+                None,
+                None::<&mut fs::File>,
+            )?;
+            Ok(from_utf8(stdout.get_ref())?.to_string())
+        };
+
+        // ranges must fall on line boundaries, within the log
+        assert!(export(1, None).is_err());
+        assert!(export(0, Some(5)).is_err());
+        assert!(export(0, Some(100)).is_err());
+
+        // a range spanning segments, which keeps its offsets in the new log
+        let archive = export(4, Some(14))?;
+        run_import(&archive[..], &target, 1024, false)?;
+        assert_eq!(read(&target)?, "two\nthree\n");
+        let mut stdout = io::Cursor::new(Vec::new());
+        run_read(
+            &mut stdout,
+            &target,
+            8,
+            false,
+            None,
+            None,
+            None::<&mut fs::File>,
+        )?;
+        assert_eq!(from_utf8(stdout.get_ref())?, "three\n");
+
+        // the next archive must pick up where the last left off
+        assert!(run_import(&export(0, Some(4))?[..], &target, 1024, false).is_err());
+        run_import(&export(14, None)?[..], &target, 1024, false)?;
+        assert_eq!(read(&target)?, "two\nthree\nfour\n");
+
+        // several contiguous archives can be imported in one go
+        let target = dir.path().join("concatenated");
+        let archives = [export(0, Some(8))?, export(8, None)?].concat();
+        run_import(&archives[..], &target, 1024, false)?;
+        assert_eq!(read(&target)?, "one\ntwo\nthree\nfour\n");
+
+        // truncated archives are reported, without importing any of them
+        let archive = export(0, None)?;
+        let truncated = dir.path().join("truncated");
+        assert!(
+            run_import(&archive[..archive.len() - 3], &truncated, 1024, false).is_err()
+        );
+        assert!(!truncated.exists());
+        run_write(io::Cursor::new("five\n"), &source, 8)?;
+        let archive = export(19, None)?;
+        assert!(
+            run_import(&archive[..archive.len() - 2], &target, 1024, false).is_err()
+        );
+        assert_eq!(read(&target)?, "one\ntwo\nthree\nfour\n");
+        assert!(!target.join("import.tmp").exists());
+
+        // as are archives whose range ends before it starts
+        let header = serde_json::json!({
+            "format": "x-log-archive",
+            "version": 1,
+            "source": "backwards",
+            "from": 4,
+            "to": 0,
+            "exported_at": "1970-01-01T00:00:00Z",
+        });
+        let archive = format!("{}\n", header);
+        assert!(run_import(archive.as_bytes(), &truncated, 1024, false).is_err());
+        assert!(!truncated.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn log_segments() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path();
+        run_write(io::Cursor::new("one\ntwo\n"), path, 8)?;
+
+        // segments added since they were loaded are seen once they're refreshed
+        let mut segments = Segments::load(path)?;
+        run_write(io::Cursor::new("three\n"), path, 8)?;
+        assert_eq!(segments.get(8), None);
+        segments.refresh()?;
+        let segment = path.join(format!("{:020}", 8));
+        assert_eq!(segments.get(8), Some(segment.as_path()));
+
+        assert_eq!(segments.find(4)?.map(|(offset, _)| offset), Some(0));
+        assert_eq!(segments.find(13)?, Some((8, segment)));
+        assert_eq!(segments.find(14)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn log_manifest() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("nested");
+        let path = &path;
+
+        set_layout(path, Layout::Nested)?;
+        run_write(io::Cursor::new("one\ntwo\nthree\n"), path, 8)?;
+
+        let manifest = Manifest::load(path)?;
+        let segments: Vec<_> = manifest
+            .segments
+            .iter()
+            .map(|x| (x.offset, x.path.as_str(), x.size, x.sealed))
+            .collect();
+        assert_eq!(
+            segments,
+            vec![
+                (0, "0000000000/00000000000000000000", 8, true),
+                (8, "0000000000/00000000000000000008", 6, false),
+            ]
+        );
+        assert_eq!(
+            manifest.segments[0].checksum,
+            Some(format!("{:08x}", crc32fast::hash(b"one\ntwo\n")))
+        );
+        assert_eq!(manifest.segments[1].checksum, None);
+
+        let mut stdout = io::Cursor::new(Vec::new());
+        run_read(
+            &mut stdout,
+            path,
+            0,
+            false,
+            None,
+            None,
+            None::<&mut fs::File>,
+        )?;
+        assert_eq!(from_utf8(stdout.get_ref())?, "one\ntwo\nthree\n");
+
+        // the layout is fixed when the log is created
+        assert!(set_layout(path, Layout::Flat).is_err());
+
+        // logs written before manifests were introduced
+        let path = dir.path().join("legacy");
+        let path = &path;
+        fs::create_dir(path)?;
+        fs::write(path.join(format!("{:020}", 0)), "one\n")?;
+        fs::write(path.join(format!("{:020}", 4)), "two\n")?;
+
+        let mut stdout = io::Cursor::new(Vec::new());
+        run_read(
+            &mut stdout,
+            path,
+            0,
+            false,
+            None,
+            None,
+            None::<&mut fs::File>,
+        )?;
+        assert_eq!(from_utf8(stdout.get_ref())?, "one\ntwo\n");
+
+        run_write(io::Cursor::new("three\n"), path, 1024)?;
+        let manifest = Manifest::load(path)?;
+        assert_eq!(manifest.layout, Layout::Flat);
+        assert_eq!(manifest.segments.len(), 3);
+        assert!(manifest.segments[..2]
+            .iter()
+            .all(|x| x.sealed && x.checksum.is_some()));
+
         Ok(())
     }
 }
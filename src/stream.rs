@@ -1,13 +1,21 @@
-use std::collections::HashMap;
-use std::io::{self, BufRead, BufReader, Write};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Seek, Write};
 use std::net;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
+use std::time;
 
-use anyhow::Result;
-use clap::{Command, Arg, ArgMatches};
+use anyhow::{Context, Result};
+use clap::{Arg, ArgGroup, ArgMatches, Command};
 use serde::{Deserialize, Serialize};
 use serde_json;
+use sha1::{Digest, Sha1};
 use uuid::Uuid;
 
 pub fn configure_app(app: Command) -> Command {
@@ -16,26 +24,283 @@ pub fn configure_app(app: Command) -> Command {
         .about("Network utilities")
         .subcommand_required(true)
         .disable_help_subcommand(true)
-        .arg(
-            Arg::new("port")
-                .short('p')
-                .long("port")
-                .help("TCP port to listen on")
-                .required(true)
-                .takes_value(true),
-        )
-        .subcommand(Command::new("http").about(
-            "Serve HTTP. Requests are written to STDOUT and \
+        .subcommand(
+            Command::new("http")
+                .about(
+                    "Serve HTTP. Requests are written to STDOUT and \
                     responses are read from STDIN",
-        ))
+                )
+                .arg(port_arg())
+                .arg(
+                    Arg::new("timeout")
+                        .long("timeout")
+                        .help(
+                            "milliseconds to wait for a response before replying \
+                            with 504 Gateway Timeout",
+                        )
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("tls-cert")
+                        .long("tls-cert")
+                        .help(
+                            "serve HTTPS, using this PEM encoded certificate chain. \
+                            The certificate and key are reloaded on SIGHUP. WebSocket \
+                            upgrades aren't supported over HTTPS, and get a 501",
+                        )
+                        .requires("tls-key")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("tls-key")
+                        .long("tls-key")
+                        .help("PEM encoded private key for --tls-cert")
+                        .requires("tls-cert")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("max-body")
+                        .long("max-body")
+                        .help(
+                            "maximum size in bytes of request bodies and WebSocket \
+                            messages. Larger requests are rejected with 413 Payload \
+                            Too Large, larger messages close their connection",
+                        )
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("chunk-size")
+                        .long("chunk-size")
+                        .help(
+                            "stream request bodies larger than this many bytes, or of \
+                            unknown length, as a sequence of http.request.chunk \
+                            packets of this size",
+                        )
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("keep-alive")
+                        .long("keep-alive")
+                        .help(
+                            "milliseconds an event stream can be idle before it's sent \
+                            a keep-alive comment, and the longest an idle WebSocket \
+                            goes between pings",
+                        )
+                        .takes_value(true)
+                        .default_value("15000"),
+                )
+                .arg(
+                    Arg::new("static")
+                        .long("static")
+                        .help(
+                            "serve files from this directory, under an optional URL \
+                            prefix. Requests for other paths are written to STDOUT",
+                        )
+                        .value_names(&["dir", "prefix"])
+                        .min_values(1)
+                        .max_values(2),
+                )
+                .arg(
+                    Arg::new("workers")
+                        .long("workers")
+                        .help(
+                            "start this many handler processes, writing requests to \
+                            them in place of STDOUT, and reading their responses from \
+                            their STDOUT. Crashed workers are restarted",
+                        )
+                        .requires("handler")
+                        .takes_value(true)
+                        .validator(|x| match x.parse::<usize>() {
+                            Ok(count) if count >= 1 => Ok(()),
+                            _ => Err("must be at least 1"),
+                        }),
+                )
+                .arg(
+                    Arg::new("cgi")
+                        .long("cgi")
+                        .help(
+                            "start a handler process for each request, CGI style: the \
+                            request is passed in environment variables, and its body on \
+                            STDIN. The process's STDOUT, with an optional header block, \
+                            is the response",
+                        )
+                        .requires("handler")
+                        .conflicts_with("workers"),
+                )
+                .group(ArgGroup::new("mode").args(&["workers", "cgi"]))
+                .arg(
+                    Arg::new("max-in-flight")
+                        .long("max-in-flight")
+                        .help(
+                            "maximum number of requests, including open event streams \
+                            and WebSockets, to handle at once. More are queued",
+                        )
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("max-queue")
+                        .long("max-queue")
+                        .help(
+                            "maximum number of requests to queue once --max-in-flight \
+                            is reached. More are rejected with 503 Service Unavailable",
+                        )
+                        .takes_value(true)
+                        .default_value("100"),
+                )
+                .arg(
+                    Arg::new("retry-after")
+                        .long("retry-after")
+                        .help("seconds rejected clients are told to wait before retrying")
+                        .takes_value(true)
+                        .default_value("1"),
+                )
+                .arg(
+                    Arg::new("metrics")
+                        .long("metrics")
+                        .help(
+                            "write an http.metrics packet, with the number of requests \
+                            in flight and queued, every this many milliseconds",
+                        )
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("shutdown-timeout")
+                        .long("shutdown-timeout")
+                        .help(
+                            "milliseconds to wait for pending requests to be answered \
+                            on SIGTERM, or once STDIN is closed, before responding to \
+                            them with 503 Service Unavailable and exiting",
+                        )
+                        .takes_value(true)
+                        .default_value("5000"),
+                )
+                .arg(
+                    Arg::new("cors")
+                        .long("cors")
+                        .help(
+                            "allow cross-origin requests from this origin, or from any \
+                            with *. Preflight requests are answered without writing \
+                            them to STDOUT",
+                        )
+                        .takes_value(true)
+                        .multiple_occurrences(true),
+                )
+                .arg(
+                    Arg::new("cors-methods")
+                        .long("cors-methods")
+                        .help("methods to allow cross-origin requests with")
+                        .takes_value(true)
+                        .default_value("GET, HEAD, POST, PUT, PATCH, DELETE"),
+                )
+                .arg(
+                    Arg::new("cors-headers")
+                        .long("cors-headers")
+                        .help(
+                            "request headers to allow cross-origin requests with. By \
+                            default, those a preflight request asks for are allowed",
+                        )
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("compress")
+                        .long("compress")
+                        .help(
+                            "compress responses with gzip or deflate, as the client's \
+                            Accept-Encoding header prefers. Streamed responses aren't \
+                            compressed",
+                        ),
+                )
+                .arg(
+                    Arg::new("request-id")
+                        .long("request-id")
+                        .help("add an X-Request-Id header, the request's request_id, to responses"),
+                )
+                .arg(
+                    Arg::new("htpasswd")
+                        .long("htpasswd")
+                        .help(
+                            "require Basic authentication, with the user:password lines \
+                            of this file. Passwords are plain text or {SHA} hashes",
+                        )
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("tokens")
+                        .long("tokens")
+                        .help(
+                            "require Bearer authentication, with the name:token lines of \
+                            this file. With --htpasswd, either is accepted",
+                        )
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("proxy")
+                        .long("proxy")
+                        .help(
+                            "forward requests to this upstream server, and respond with \
+                            its responses, in place of those read from STDIN. Requests \
+                            are still written to STDOUT, followed by an \
+                            http.proxy.response packet with the full response",
+                        )
+                        .takes_value(true)
+                        .conflicts_with_all(&["workers", "cgi"]),
+                )
+                .arg(
+                    Arg::new("routes")
+                        .long("routes")
+                        .help(
+                            "JSON file of routes, each matching a method and path \
+                            pattern to a topic, handler, CGI command or static \
+                            directory. See README",
+                        )
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("handler")
+                        .help("command, and its arguments, to start --workers or --cgi with")
+                        .requires("mode")
+                        .multiple_values(true)
+                        .last(true),
+                ),
+        )
         .subcommand(
-            Command::new("merge").about(
-                "Read lines from TCP connections and write them serially to STDOUT",
-            ),
+            Command::new("merge")
+                .about("Read lines from TCP connections and write them serially to STDOUT")
+                .arg(port_arg()),
+        )
+        .subcommand(
+            Command::new("http-client")
+                .about(
+                    "Make HTTP requests. http.request packets are read from STDIN \
+                    and http.client.response packets are written to STDOUT",
+                )
+                .arg(
+                    Arg::new("concurrency")
+                        .long("concurrency")
+                        .help("maximum number of requests to make at once")
+                        .takes_value(true)
+                        .default_value("8"),
+                )
+                .arg(
+                    Arg::new("timeout")
+                        .long("timeout")
+                        .help("milliseconds to wait for each response")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("base-url")
+                        .long("base-url")
+                        .help(
+                            "send requests to this server, in place of the one in their \
+                            URL, e.g. to replay them against a local server",
+                        )
+                        .takes_value(true),
+                ),
         )
         .subcommand(
             Command::new("broadcast")
                 .about("Read lines from STDIN and write them to all TCP connections")
+                .arg(port_arg())
                 .arg(
                     Arg::new("history")
                         .short('i')
@@ -47,12 +312,110 @@ pub fn configure_app(app: Command) -> Command {
         );
 }
 
+/// The TCP port that each subcommand but http-client listens on.
+fn port_arg<'help>() -> Arg<'help> {
+    Arg::new("port")
+        .short('p')
+        .long("port")
+        .help("TCP port to listen on")
+        .required(true)
+        .takes_value(true)
+}
+
 pub fn run(matches: &ArgMatches) -> Result<()> {
-    let port: u16 = matches.value_of_t("port").unwrap_or_else(|e| e.exit());
+    if let Some(("http-client", matches)) = matches.subcommand() {
+        let timeout: Option<u64> = value_of(matches, "timeout");
+        return run_http_client(ClientOptions {
+            concurrency: matches
+                .value_of_t::<usize>("concurrency")
+                .unwrap_or_else(|e| e.exit())
+                .max(1),
+            timeout: timeout.map(time::Duration::from_millis),
+            base_url: matches
+                .value_of("base-url")
+                .map(|x| x.trim_end_matches('/').to_string()),
+        });
+    }
+
+    // the rest listen on the port they each require
+    let (_, listener) = matches.subcommand().unwrap();
+    let port: u16 = listener.value_of_t("port").unwrap_or_else(|e| e.exit());
     let sock =
         net::SocketAddr::new(net::IpAddr::V4(net::Ipv4Addr::new(0, 0, 0, 0)), port);
     match matches.subcommand() {
-        Some(("http", _)) => run_http(sock)?,
+        Some(("http", matches)) => {
+            let timeout: Option<u64> = value_of(matches, "timeout");
+            let tls = match (matches.value_of("tls-cert"), matches.value_of("tls-key")) {
+                (Some(cert), Some(key)) => {
+                    Some((PathBuf::from(cert), PathBuf::from(key)))
+                }
+                _ => None,
+            };
+            let static_files = matches.values_of("static").map(|mut values| {
+                let dir = PathBuf::from(values.next().unwrap());
+                let prefix = values.next().unwrap_or("/");
+                (dir, prefix.trim_end_matches('/').to_string())
+            });
+            let options = HttpOptions {
+                timeout: timeout.map(time::Duration::from_millis),
+                tls,
+                max_body: value_of(matches, "max-body"),
+                chunk_size: value_of(matches, "chunk-size"),
+                keep_alive: time::Duration::from_millis(
+                    matches
+                        .value_of_t("keep-alive")
+                        .unwrap_or_else(|e| e.exit()),
+                ),
+                static_files,
+                workers: value_of(matches, "workers").map(|count| {
+                    let handler = matches.values_of("handler").unwrap();
+                    Arc::new(Workers::new(count, handler.map(String::from).collect()))
+                }),
+                cgi: matches.is_present("cgi").then(|| {
+                    let handler = matches.values_of("handler").unwrap();
+                    handler.map(String::from).collect()
+                }),
+                routes: match matches.value_of("routes") {
+                    Some(path) => load_routes(Path::new(path))?,
+                    None => Vec::new(),
+                },
+                max_in_flight: value_of(matches, "max-in-flight"),
+                max_queue: matches.value_of_t("max-queue").unwrap_or_else(|e| e.exit()),
+                retry_after: matches
+                    .value_of_t("retry-after")
+                    .unwrap_or_else(|e| e.exit()),
+                metrics: value_of(matches, "metrics").map(time::Duration::from_millis),
+                shutdown_timeout: time::Duration::from_millis(
+                    matches
+                        .value_of_t("shutdown-timeout")
+                        .unwrap_or_else(|e| e.exit()),
+                ),
+                cors: matches.values_of("cors").map(|origins| Cors {
+                    origins: origins.map(String::from).collect(),
+                    methods: matches.value_of("cors-methods").unwrap().to_string(),
+                    headers: matches.value_of("cors-headers").map(String::from),
+                }),
+                compress: matches.is_present("compress"),
+                request_id: matches.is_present("request-id"),
+                proxy: matches.value_of("proxy").map(|upstream| {
+                    let mut agent = ureq::AgentBuilder::new().redirects(0);
+                    if let Some(timeout) = value_of(matches, "timeout") {
+                        agent = agent.timeout(time::Duration::from_millis(timeout));
+                    }
+                    Proxy {
+                        upstream: upstream.trim_end_matches('/').to_string(),
+                        agent: agent.build(),
+                    }
+                }),
+                auth: match (matches.value_of("htpasswd"), matches.value_of("tokens")) {
+                    (None, None) => None,
+                    (htpasswd, tokens) => {
+                        Some(Auth::load(htpasswd.map(Path::new), tokens.map(Path::new))?)
+                    }
+                },
+            };
+            run_http(sock, options)?
+        }
         Some(("merge", _)) => run_merge(sock)?,
         Some(("broadcast", matches)) => {
             let history: usize =
@@ -64,127 +427,2665 @@ pub fn run(matches: &ArgMatches) -> Result<()> {
     Ok(())
 }
 
-fn run_http(sock: net::SocketAddr) -> Result<()> {
-    #[derive(Serialize, Deserialize)]
-    struct Response {
-        request_id: String,
-        body: String,
-        headers: Option<Vec<(String, String)>>,
+/// A response packet read from STDIN. A response is either complete in a single
+/// packet, or, when `stream` is set, is the head of a streamed response: its
+/// body, and the body of each following packet for the same `request_id`, is sent
+/// to the client as a chunk, until a packet with `end` set.
+///
+/// With `event_stream` set, the response is a stream of server-sent events
+/// instead: the body of each packet is sent as an event's `data`, named by the
+/// packet's `event`, and with its `id`.
+#[derive(Serialize, Deserialize, Default)]
+struct Response {
+    request_id: String,
+    status: Option<u16>,
+    /// the reason phrase to send in place of the status's standard one
+    reason: Option<String>,
+    #[serde(default)]
+    body: String,
+    // "utf8", the default, or "base64" for binary bodies
+    encoding: Option<String>,
+    headers: Option<Vec<(String, String)>>,
+    #[serde(default)]
+    stream: bool,
+    #[serde(default)]
+    end: bool,
+    #[serde(default)]
+    event_stream: bool,
+    event: Option<String>,
+    id: Option<String>,
+}
+
+impl Response {
+    fn decode_body(&self) -> Result<Vec<u8>, &'static str> {
+        decode_body(&self.body, self.encoding.as_deref())
     }
 
-    let requests: HashMap<String, mpsc::Sender<Response>> = HashMap::new();
-    let requests = Arc::new(Mutex::new(requests));
+    /// The response's headers, with a default Content-Type if it doesn't have one.
+    fn headers(&self) -> Vec<(String, String)> {
+        let mut headers = self.headers.clone().unwrap_or_default();
+        if !headers
+            .iter()
+            .any(|(key, _)| key.eq_ignore_ascii_case("Content-Type"))
+        {
+            let content_type = match self.encoding.as_deref() {
+                _ if self.event_stream => "text/event-stream",
+                Some("base64") => "application/octet-stream",
+                _ => "text/html; charset=utf8",
+            };
+            headers.insert(0, ("Content-Type".to_string(), content_type.to_string()));
+        }
+        if self.event_stream
+            && !headers
+                .iter()
+                .any(|(key, _)| key.eq_ignore_ascii_case("Cache-Control"))
+        {
+            headers.push(("Cache-Control".to_string(), "no-cache".to_string()));
+        }
+        headers
+    }
 
-    {
-        let requests = requests.clone();
-        thread::spawn(move || {
-            let stdin = io::stdin();
-            let buf = BufReader::new(stdin);
-            for line in buf.lines() {
-                let line = line.unwrap();
+    fn streaming(&self) -> bool {
+        self.stream || self.event_stream
+    }
 
-                let res = serde_json::from_str(&line);
-                if res.is_err() {
-                    println!(
-                        "{}",
-                        serde_json::json!({
-                            "topic": "http.response.log",
-                            "content": line,
-                            "severity": "ERROR",
-                            "error": "unable to parse response",
-                        })
-                    );
+    /// Formats a body as a server-sent event, one `data` line for each of its
+    /// lines. Packets without a body, event or id don't send an event.
+    fn event(&self, body: &[u8]) -> Vec<u8> {
+        let mut event = Vec::new();
+        if body.is_empty() && self.event.is_none() && self.id.is_none() {
+            return event;
+        }
+        if let Some(name) = &self.event {
+            event.extend(format!("event: {}\n", name).into_bytes());
+        }
+        if let Some(id) = &self.id {
+            event.extend(format!("id: {}\n", id).into_bytes());
+        }
+        for line in body.split(|b| *b == b'\n') {
+            event.extend(b"data: ");
+            event.extend(line.strip_suffix(b"\r").unwrap_or(line));
+            event.push(b'\n');
+        }
+        event.push(b'\n');
+        event
+    }
+}
+
+fn decode_body(body: &str, encoding: Option<&str>) -> Result<Vec<u8>, &'static str> {
+    match encoding {
+        None | Some("utf8") => Ok(body.as_bytes().to_vec()),
+        // accept both the URL safe alphabet, used for request bodies, and the
+        // standard one
+        Some("base64") => base64::decode_config(body, base64::URL_SAFE)
+            .or_else(|_| base64::decode(body))
+            .map_err(|_| "unable to decode body"),
+        Some(_) => Err("unknown encoding"),
+    }
+}
+
+/// A message read from STDIN for an open WebSocket connection. Its body is sent as
+/// a text frame, or as a binary frame when base64 encoded. With `close` set, the
+/// connection is closed instead.
+#[derive(Serialize, Deserialize)]
+struct WsMessage {
+    connection_id: String,
+    #[serde(default)]
+    body: String,
+    // "utf8", the default, or "base64" for binary frames
+    encoding: Option<String>,
+    #[serde(default)]
+    close: bool,
+}
+
+/// A request waiting on its response. Streamed responses remain pending until
+/// their end packet is read.
+struct Pending {
+    tx: mpsc::Sender<Response>,
+    streaming: bool,
+    /// the worker handling the request, when there's a pool of them
+    worker: Option<usize>,
+}
+
+fn log_response(res: &Response, error: Option<&str>) {
+    let packet = match error {
+        Some(error) => serde_json::json!({
+            "topic": "http.response.log",
+            "content": res,
+            "severity": "ERROR",
+            "error": error,
+        }),
+        None => serde_json::json!({
+            "topic": "http.response.log",
+            "content": res,
+            "severity": "INFO",
+        }),
+    };
+    println!("{}", packet);
+}
+
+/// A request, logged as an http.access.log packet once it's been responded to,
+/// however that was.
+struct Access {
+    request_id: Uuid,
+    method: String,
+    path: String,
+    remote_addr: net::SocketAddr,
+    /// the length of the request's body
+    request_bytes: usize,
+    /// when the request was written to the handler
+    sent: time::Instant,
+    /// how long the handler took to respond, if it did
+    latency: Option<time::Duration>,
+}
+
+impl Access {
+    fn new(req: &tiny_http::Request) -> Access {
+        let url = normalize_url(req);
+        Access {
+            request_id: Uuid::new_v4(),
+            method: req.method().to_string(),
+            path: percent_encoding::percent_decode_str(url.path())
+                .decode_utf8_lossy()
+                .to_string(),
+            remote_addr: *req.remote_addr(),
+            request_bytes: 0,
+            sent: time::Instant::now(),
+            latency: None,
+        }
+    }
+
+    fn log(&self, status: u16, response_bytes: usize) {
+        println!(
+            "{}",
+            serde_json::json!({
+                "topic": "http.access.log",
+                "content": {
+                    "request_id": self.request_id,
+                    "method": self.method,
+                    "path": self.path,
+                    "remote_addr": self.remote_addr,
+                    "status": status,
+                    "request_bytes": self.request_bytes,
+                    "response_bytes": response_bytes,
+                    "latency": self.latency.map(|x| x.as_secs_f64() * 1000.0),
+                },
+                "severity": "INFO",
+            })
+        );
+    }
+}
+
+/// Writes a streamed response directly to the connection, using chunked transfer
+/// encoding, flushing each chunk as it arrives. Event streams are sent a
+/// keep-alive comment whenever they're idle for `keep_alive`; HTTP/1.0 clients get
+/// them unchunked, ended by closing the connection. The length of the body written
+/// is added to `written`.
+fn respond_streamed(
+    req: tiny_http::Request,
+    res: &Response,
+    body: Vec<u8>,
+    rx: &mpsc::Receiver<Response>,
+    keep_alive: time::Duration,
+    extra: Vec<tiny_http::Header>,
+    written: &mut usize,
+) -> io::Result<()> {
+    let status = tiny_http::StatusCode(res.status.unwrap_or(200));
+    let reason = res.reason.as_deref();
+    let chunked = req.http_version() != &tiny_http::HTTPVersion(1, 0);
+    let event_stream = res.event_stream;
+
+    let mut w = req.into_writer();
+    write!(
+        w,
+        "HTTP/1.1 {} {}\r\n",
+        status.0,
+        reason.unwrap_or_else(|| status.default_reason_phrase())
+    )?;
+    for (key, value) in res.headers() {
+        let framing = ["Content-Length", "Transfer-Encoding", "Connection"];
+        if framing.iter().any(|x| key.eq_ignore_ascii_case(x)) {
+            continue;
+        }
+        write!(w, "{}: {}\r\n", key, value)?;
+    }
+    for header in extra {
+        write!(w, "{}: {}\r\n", header.field, header.value)?;
+    }
+    if chunked {
+        write!(w, "Transfer-Encoding: chunked\r\n\r\n")?;
+    } else {
+        write!(w, "Connection: close\r\n\r\n")?;
+    }
+    w.flush()?;
+
+    let mut chunk = if event_stream { res.event(&body) } else { body };
+    let mut end = res.end;
+    loop {
+        if !chunk.is_empty() {
+            if chunked {
+                write!(w, "{:x}\r\n", chunk.len())?;
+            }
+            w.write_all(&chunk)?;
+            if chunked {
+                write!(w, "\r\n")?;
+            }
+            w.flush()?;
+            *written += chunk.len();
+        }
+        if end {
+            break;
+        }
+
+        let res = if event_stream {
+            match rx.recv_timeout(keep_alive) {
+                Ok(res) => res,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    chunk = b": keep-alive\n\n".to_vec();
                     continue;
                 }
-                let res: Response = res.unwrap();
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+        } else {
+            match rx.recv() {
+                Ok(res) => res,
+                Err(_) => break,
+            }
+        };
+        match res.decode_body() {
+            Ok(body) => {
+                log_response(&res, None);
+                chunk = if event_stream { res.event(&body) } else { body };
+                end = res.end;
+            }
+            Err(error) => {
+                log_response(&res, Some(error));
+                break;
+            }
+        }
+    }
 
-                let mut requests = requests.lock().expect("poisoned");
-                if let Some(tx) = requests.remove(&res.request_id) {
-                    tx.send(res).unwrap();
-                } else {
-                    println!(
-                        "{}",
-                        serde_json::json!({
-                            "topic": "http.response.log",
-                            "content": res,
-                            "severity": "ERROR",
-                            "error": "unknown request_id",
-                        })
-                    );
+    if chunked {
+        write!(w, "0\r\n\r\n")?;
+    }
+    w.flush()
+}
+
+/// A route, read from the --routes file.
+#[derive(Deserialize)]
+struct RouteConfig {
+    /// any method when absent
+    method: Option<String>,
+    path: String,
+    topic: Option<String>,
+    handler: Option<Vec<String>>,
+    #[serde(default = "default_route_workers")]
+    workers: usize,
+    cgi: Option<Vec<String>>,
+    #[serde(rename = "static")]
+    static_dir: Option<PathBuf>,
+}
+
+fn default_route_workers() -> usize {
+    1
+}
+
+/// A segment of a route's path pattern.
+enum Segment {
+    Literal(String),
+    /// `:name`, matches any one segment
+    Param(String),
+    /// `*name`, matches the rest of the path, and must be last
+    Rest(String),
+}
+
+/// Where requests matching a route go.
+enum Destination {
+    /// STDOUT, as packets with this topic
+    Topic(String),
+    Workers(Arc<Workers>),
+    Cgi(Vec<String>),
+    Static(PathBuf),
+}
+
+struct Route {
+    method: Option<String>,
+    pattern: Vec<Segment>,
+    destination: Destination,
+}
+
+impl Route {
+    fn parse(config: RouteConfig) -> Result<Route> {
+        let mut pattern = Vec::new();
+        let segments: Vec<&str> =
+            config.path.split('/').filter(|x| !x.is_empty()).collect();
+        for (i, segment) in segments.iter().enumerate() {
+            pattern.push(if let Some(name) = segment.strip_prefix(':') {
+                Segment::Param(name.to_string())
+            } else if let Some(name) = segment.strip_prefix('*') {
+                if i + 1 != segments.len() {
+                    anyhow::bail!("`{}`: * must be the last segment", config.path);
                 }
+                Segment::Rest(name.to_string())
+            } else {
+                Segment::Literal(segment.to_string())
+            });
+        }
+
+        let mut destinations = Vec::new();
+        if let Some(topic) = config.topic {
+            destinations.push(Destination::Topic(topic));
+        }
+        if let Some(handler) = config.handler.filter(|x| !x.is_empty()) {
+            if config.workers < 1 {
+                anyhow::bail!("`{}`: workers must be at least 1", config.path);
             }
-        });
+            destinations.push(Destination::Workers(Arc::new(Workers::new(
+                config.workers,
+                handler,
+            ))));
+        }
+        if let Some(command) = config.cgi.filter(|x| !x.is_empty()) {
+            destinations.push(Destination::Cgi(command));
+        }
+        if let Some(dir) = config.static_dir {
+            destinations.push(Destination::Static(dir));
+        }
+        if destinations.len() != 1 {
+            anyhow::bail!(
+                "`{}`: routes need exactly one of topic, handler, cgi or static",
+                config.path
+            );
+        }
+
+        Ok(Route {
+            method: config.method,
+            pattern,
+            destination: destinations.pop().unwrap(),
+        })
     }
 
-    let server = tiny_http::Server::http(sock).unwrap();
-    for mut req in server.incoming_requests() {
-        let requests = requests.clone();
-        thread::spawn(move || {
-            let uid = Uuid::new_v4();
+    /// The path parameters of a matching request, and the rest of its path, when
+    /// the pattern ends with `*`.
+    fn matches(
+        &self,
+        method: &tiny_http::Method,
+        path: &str,
+    ) -> Option<(HashMap<String, String>, Option<String>)> {
+        if let Some(expected) = &self.method {
+            if !expected.eq_ignore_ascii_case(method.as_str()) {
+                return None;
+            }
+        }
+
+        let segments: Vec<&str> = path.split('/').filter(|x| !x.is_empty()).collect();
+        let mut params = HashMap::new();
+        for (i, segment) in self.pattern.iter().enumerate() {
+            match segment {
+                Segment::Literal(literal) => {
+                    if segments.get(i) != Some(&literal.as_str()) {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    params.insert(name.clone(), segments.get(i)?.to_string());
+                }
+                Segment::Rest(name) => {
+                    let rest = segments[i.min(segments.len())..].join("/");
+                    if !name.is_empty() {
+                        params.insert(name.clone(), rest.clone());
+                    }
+                    return Some((params, Some(rest)));
+                }
+            }
+        }
+        if segments.len() != self.pattern.len() {
+            return None;
+        }
+        Some((params, None))
+    }
+}
 
-            let mut buffer = String::new();
-            req.as_reader().read_to_string(&mut buffer).unwrap();
-            let b64 = base64::encode_config(buffer, base64::URL_SAFE);
+fn load_routes(path: &Path) -> Result<Vec<Route>> {
+    let config = fs::read_to_string(path)
+        .with_context(|| format!("could not read routes `{}`", path.display()))?;
+    let config: Vec<RouteConfig> = serde_json::from_str(&config)
+        .with_context(|| format!("could not parse routes `{}`", path.display()))?;
+    config.into_iter().map(Route::parse).collect()
+}
 
-            // gosh, this is terrible. I need to get better with rust's type system
-            let headers: Vec<(String, String)> = req
-                .headers()
-                .iter()
-                .map(|x| (format!("{}", x.field.as_str()), format!("{}", x.value)))
-                .collect();
+/// Parses an optional argument, exiting with a usage error if it's invalid.
+fn value_of<T>(matches: &ArgMatches, name: &str) -> Option<T>
+where
+    T: FromStr,
+    <T as FromStr>::Err: fmt::Display,
+{
+    matches
+        .is_present(name)
+        .then(|| matches.value_of_t(name).unwrap_or_else(|e| e.exit()))
+}
 
-            let packet = serde_json::json!({
-                "topic": "http.request",
-                "content": {
-                    "method": req.method().as_str(),
-                    "headers": headers,
-                    "remote_addr": req.remote_addr(),
-                    "url": req.url(),
-                    "body": b64,
-                    "request_id": uid,
-                },
-            });
+struct HttpOptions {
+    /// how long to wait for a response before giving up on the request
+    timeout: Option<time::Duration>,
+    /// paths of the PEM encoded certificate chain and private key to serve HTTPS
+    /// with
+    tls: Option<(PathBuf, PathBuf)>,
+    /// requests with larger bodies are rejected
+    max_body: Option<usize>,
+    /// requests with larger bodies have them streamed in chunks of this size
+    chunk_size: Option<usize>,
+    /// how often idle event streams are sent a keep-alive comment
+    keep_alive: time::Duration,
+    /// directory to serve files from, and the URL prefix to serve them under
+    static_files: Option<(PathBuf, String)>,
+    /// handler processes to write requests to in place of STDOUT
+    workers: Option<Arc<Workers>>,
+    /// the command to start a process with for each request, CGI style
+    cgi: Option<Vec<String>>,
+    /// routes for requests, tried in order before the options above
+    routes: Vec<Route>,
+    /// how many requests are handled at once; more are queued, up to `max_queue`
+    max_in_flight: Option<usize>,
+    max_queue: usize,
+    /// seconds clients are told to wait before retrying rejected requests
+    retry_after: u64,
+    /// how often to write http.metrics packets
+    metrics: Option<time::Duration>,
+    /// how long to wait for pending requests when shutting down
+    shutdown_timeout: time::Duration,
+    cors: Option<Cors>,
+    /// whether to compress responses the client accepts compressed
+    compress: bool,
+    /// whether to add an X-Request-Id header to responses
+    request_id: bool,
+    /// credentials required of every request
+    auth: Option<Auth>,
+    /// the upstream server to forward requests to in place of STDOUT
+    proxy: Option<Proxy>,
+}
+
+/// An upstream server requests are forwarded to, with --proxy.
+struct Proxy {
+    /// the server's URL, without a trailing slash
+    upstream: String,
+    agent: ureq::Agent,
+}
 
-            let (tx, rx) = mpsc::channel();
+/// Headers that only apply to a single connection, which aren't forwarded.
+const HOP_BY_HOP: &[&str] = &[
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
 
-            {
-                let mut requests = requests.lock().expect("poisoned");
-                requests.insert(uid.to_string(), tx);
+impl Proxy {
+    /// Forwards a request, and its body, to the upstream server: its response
+    /// is returned whatever its status, with a base64 encoded body.
+    fn forward(
+        &self,
+        req: &tiny_http::Request,
+        request_id: &Uuid,
+        body: &[u8],
+    ) -> Result<Response, String> {
+        let url = format!("{}{}", self.upstream, req.url());
+        let mut headers = headers(req);
+        if let Some(host) = header(req, "Host") {
+            headers.push(("X-Forwarded-Host".to_string(), host.to_string()));
+        }
+        let addr = req.remote_addr();
+        headers.push(("X-Forwarded-For".to_string(), addr.ip().to_string()));
+        let proto = if req.secure() { "https" } else { "http" };
+        headers.push(("X-Forwarded-Proto".to_string(), proto.to_string()));
+        let method = req.method().as_str();
+        let request_id = request_id.to_string();
+        fetch(&self.agent, &request_id, method, &url, &headers, body)
+    }
+}
+
+/// Makes a request, returning its response whatever its status, with a base64
+/// encoded body. Headers that don't apply to the new connection are dropped.
+fn fetch(
+    agent: &ureq::Agent,
+    request_id: &str,
+    method: &str,
+    url: &str,
+    headers: &[(String, String)],
+    body: &[u8],
+) -> Result<Response, String> {
+    let mut request = agent.request(method, url);
+    for (name, value) in headers {
+        let skip = ["Host", "Content-Length"].iter().chain(HOP_BY_HOP);
+        if skip.into_iter().any(|x| name.eq_ignore_ascii_case(x)) {
+            continue;
+        }
+        request = request.set(name, value);
+    }
+
+    let response = match request.send_bytes(body) {
+        Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+        Err(e) => return Err(e.to_string()),
+    };
+    let status = response.status();
+    let mut headers = Vec::new();
+    for name in response.headers_names() {
+        let skip = ["Content-Length"].iter().chain(HOP_BY_HOP);
+        if skip.into_iter().any(|x| name.eq_ignore_ascii_case(x)) {
+            continue;
+        }
+        for value in response.all(&name) {
+            headers.push((name.clone(), value.to_string()));
+        }
+    }
+    let mut body = Vec::new();
+    response
+        .into_reader()
+        .read_to_end(&mut body)
+        .map_err(|e| e.to_string())?;
+    Ok(Response {
+        request_id: request_id.to_string(),
+        status: Some(status),
+        body: base64::encode_config(&body, base64::URL_SAFE),
+        encoding: Some("base64".to_string()),
+        headers: Some(headers),
+        ..Default::default()
+    })
+}
+
+/// A request read from STDIN by http-client: the content of an http.request
+/// packet.
+#[derive(Deserialize)]
+struct ClientRequest {
+    request_id: Option<String>,
+    method: String,
+    /// absolute, or a path resolved against --base-url or `normalized_url`
+    url: String,
+    normalized_url: Option<String>,
+    #[serde(default)]
+    headers: Vec<(String, String)>,
+    body: Option<String>,
+    /// "base64", the default, or "utf8"
+    encoding: Option<String>,
+}
+
+struct ClientOptions {
+    concurrency: usize,
+    timeout: Option<time::Duration>,
+    /// the server to send requests to, in place of the one in their URL
+    base_url: Option<String>,
+}
+
+/// Reads requests from STDIN and makes them, `concurrency` at a time, writing
+/// their responses to STDOUT.
+fn run_http_client(options: ClientOptions) -> Result<()> {
+    let mut agent = ureq::AgentBuilder::new().redirects(0);
+    if let Some(timeout) = options.timeout {
+        agent = agent.timeout(timeout);
+    }
+    let agent = agent.build();
+    let options = Arc::new(options);
+
+    // the channel is bounded so requests aren't read far ahead of being made
+    let (tx, rx) = mpsc::sync_channel::<ClientRequest>(options.concurrency);
+    let rx = Arc::new(Mutex::new(rx));
+    let threads: Vec<_> = (0..options.concurrency)
+        .map(|_| {
+            let rx = rx.clone();
+            let agent = agent.clone();
+            let options = options.clone();
+            thread::spawn(move || loop {
+                let req = match rx.lock().expect("poisoned").recv() {
+                    Ok(req) => req,
+                    Err(_) => break,
+                };
+                send_request(&agent, req, &options);
+            })
+        })
+        .collect();
+
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let line = line?;
+        let packet: serde_json::Value = match serde_json::from_str(&line) {
+            Ok(packet) => packet,
+            Err(_) => {
+                log_client(&line, "unable to parse request");
+                continue;
             }
-            println!("{}", packet);
+        };
+        // packets, as read from a log, or just their content; packets which
+        // aren't requests are skipped
+        let topic = packet.get("topic").cloned();
+        let packet = match packet.get("content") {
+            Some(content) => content.clone(),
+            None => packet,
+        };
+        match serde_json::from_value(packet) {
+            Ok(req) => tx.send(req).unwrap(),
+            Err(_) if topic.is_some() => continue,
+            Err(_) => log_client(&line, "unable to parse request"),
+        }
+    }
 
-            let res = rx.recv().unwrap();
+    drop(tx);
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    Ok(())
+}
 
-            let mut http_response = tiny_http::Response::from_string(&res.body);
+fn log_client(line: &str, error: &str) {
+    println!(
+        "{}",
+        serde_json::json!({
+            "topic": "http.client.log",
+            "content": line,
+            "severity": "ERROR",
+            "error": error,
+        })
+    );
+}
 
-            let header = tiny_http::Header::from_bytes(
-                &b"Content-Type"[..],
-                &b"text/html; charset=utf8"[..],
-            )
-            .unwrap();
-            http_response = http_response.with_header(header);
+/// Makes a request read by http-client, writing an http.client.response packet.
+fn send_request(agent: &ureq::Agent, req: ClientRequest, options: &ClientOptions) {
+    let request_id = req.request_id.unwrap_or_else(|| Uuid::new_v4().to_string());
+    let url = match (&options.base_url, url::Url::parse(&req.url)) {
+        (Some(base), Ok(url)) => {
+            let query = url.query().map(|x| format!("?{}", x)).unwrap_or_default();
+            Ok(format!("{}{}{}", base, url.path(), query))
+        }
+        (Some(base), Err(_)) => Ok(format!("{}{}", base, req.url)),
+        (None, Ok(_)) => Ok(req.url.clone()),
+        (None, Err(_)) => req.normalized_url.clone().ok_or("relative url"),
+    };
+    let body = match &req.body {
+        Some(body) => {
+            decode_body(body, Some(req.encoding.as_deref().unwrap_or("base64")))
+        }
+        None => Ok(Vec::new()),
+    };
+
+    let start = time::Instant::now();
+    let res = match (url, body) {
+        (Ok(url), Ok(body)) => {
+            fetch(agent, &request_id, &req.method, &url, &req.headers, &body)
+        }
+        (Err(error), _) | (_, Err(error)) => Err(error.to_string()),
+    };
+    let latency = start.elapsed().as_secs_f64() * 1000.0;
+
+    let packet = match res {
+        Ok(res) => serde_json::json!({
+            "topic": "http.client.response",
+            "content": {
+                "request_id": res.request_id,
+                "status": res.status,
+                "headers": res.headers,
+                "body": res.body,
+                "encoding": res.encoding,
+                "latency": latency,
+            },
+        }),
+        Err(error) => serde_json::json!({
+            "topic": "http.client.response",
+            "content": {"request_id": request_id, "latency": latency},
+            "severity": "ERROR",
+            "error": error,
+        }),
+    };
+    println!("{}", packet);
+}
+
+/// Credentials requests are authenticated with, read from --htpasswd and
+/// --tokens.
+#[derive(Default)]
+struct Auth {
+    /// passwords by user, if Basic authentication is accepted
+    passwords: Option<HashMap<String, String>>,
+    /// names by token, if Bearer authentication is accepted
+    tokens: Option<HashMap<String, String>>,
+}
 
-            if let Some(ref headers) = res.headers {
-                for header in headers {
-                    let (key, value) = header;
-                    let add = tiny_http::Header::from_bytes(key.clone(), value.clone()).unwrap();
-                    http_response = http_response.with_header(add);
+impl Auth {
+    fn load(htpasswd: Option<&Path>, tokens: Option<&Path>) -> Result<Auth> {
+        let mut auth = Auth::default();
+        if let Some(path) = htpasswd {
+            let mut passwords = HashMap::new();
+            for (user, password) in read_credentials(path)? {
+                if unsupported_hash(&password) {
+                    anyhow::bail!(
+                        "`{}`: unsupported password hash for `{}`, use {{SHA}} or plain text",
+                        path.display(),
+                        user
+                    );
                 }
+                passwords.insert(user, password);
             }
+            auth.passwords = Some(passwords);
+        }
+        if let Some(path) = tokens {
+            let credentials = read_credentials(path)?;
+            auth.tokens = Some(
+                credentials
+                    .into_iter()
+                    .map(|(name, token)| (token, name))
+                    .collect(),
+            );
+        }
+        Ok(auth)
+    }
 
-            let _ = req.respond(http_response);
+    /// The principal `req` is authenticated as: the user or token's name.
+    fn principal(&self, req: &tiny_http::Request) -> Option<String> {
+        let (scheme, credentials) = header(req, "Authorization")?.split_once(' ')?;
+        let credentials = credentials.trim();
+        if scheme.eq_ignore_ascii_case("Basic") {
+            let decoded = base64::decode(credentials).ok()?;
+            let (user, password) =
+                std::str::from_utf8(&decoded).ok()?.split_once(':')?;
+            let expected = self.passwords.as_ref()?.get(user)?;
+            let matched = match expected.strip_prefix("{SHA}") {
+                Some(hash) => {
+                    let digest = base64::encode(Sha1::digest(password.as_bytes()));
+                    constant_time_eq(digest.as_bytes(), hash.as_bytes())
+                }
+                None => constant_time_eq(password.as_bytes(), expected.as_bytes()),
+            };
+            return matched.then(|| user.to_string());
+        }
+        if scheme.eq_ignore_ascii_case("Bearer") {
+            let tokens = self.tokens.as_ref()?;
+            let (_, name) = tokens.iter().find(|(token, _)| {
+                constant_time_eq(token.as_bytes(), credentials.as_bytes())
+            })?;
+            return Some(name.clone());
+        }
+        None
+    }
 
-            println!(
-                "{}",
-                serde_json::json!({
-                    "topic": "http.response.log",
-                    "content": res,
-                    "severity": "INFO",
-                })
+    /// A 401 Unauthorized response, challenging the client for the schemes
+    /// accepted.
+    fn unauthorized(&self) -> tiny_http::Response<io::Empty> {
+        let mut response = tiny_http::Response::empty(401);
+        if self.passwords.is_some() {
+            response.add_header(
+                tiny_http::Header::from_bytes("WWW-Authenticate", "Basic realm=\"x\"")
+                    .unwrap(),
             );
-        });
+        }
+        if self.tokens.is_some() {
+            response.add_header(
+                tiny_http::Header::from_bytes("WWW-Authenticate", "Bearer realm=\"x\"")
+                    .unwrap(),
+            );
+        }
+        response
     }
-    Ok(())
+}
+
+/// Reads a file of name:secret lines, skipping blank lines and # comments.
+fn read_credentials(path: &Path) -> Result<Vec<(String, String)>> {
+    let file = fs::read_to_string(path)
+        .with_context(|| format!("could not read credentials `{}`", path.display()))?;
+    let mut credentials = Vec::new();
+    for (i, line) in file.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once(':') {
+            Some((name, secret)) if !name.is_empty() && !secret.is_empty() => {
+                credentials.push((name.to_string(), secret.to_string()))
+            }
+            _ => anyhow::bail!(
+                "`{}`, line {}: expected name:secret",
+                path.display(),
+                i + 1
+            ),
+        }
+    }
+    Ok(credentials)
+}
+
+/// Whether an htpasswd password is a hash other than {SHA}: MD5, bcrypt and the
+/// like start with `$`, and crypt(3) hashes are 13 characters of [./0-9A-Za-z].
+/// Taking one for plain text would make the hash itself the password.
+fn unsupported_hash(password: &str) -> bool {
+    let crypt = password.len() == 13
+        && password
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'/');
+    password.starts_with('$') || crypt
+}
+
+/// Compares secrets in time independent of where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// The cross-origin requests to allow.
+struct Cors {
+    /// allowed origins, with * allowing any
+    origins: Vec<String>,
+    methods: String,
+    /// allowed request headers: those asked for, when absent
+    headers: Option<String>,
+}
+
+impl Cors {
+    /// Whether `req` is a preflight request, to be answered without a handler.
+    fn preflight(req: &tiny_http::Request) -> bool {
+        req.method() == &tiny_http::Method::Options
+            && header(req, "Origin").is_some()
+            && header(req, "Access-Control-Request-Method").is_some()
+    }
+
+    /// The headers to respond to `req` with: none unless it's from an allowed
+    /// origin.
+    fn headers(&self, req: &tiny_http::Request) -> Vec<tiny_http::Header> {
+        let origin = match header(req, "Origin") {
+            Some(origin) if self.origins.iter().any(|x| x == "*" || x == origin) => {
+                origin
+            }
+            _ => return Vec::new(),
+        };
+        let mut headers =
+            vec![("Access-Control-Allow-Origin", origin), ("Vary", "Origin")];
+        if Cors::preflight(req) {
+            headers.push(("Access-Control-Allow-Methods", &self.methods));
+            let allowed = self
+                .headers
+                .as_deref()
+                .or_else(|| header(req, "Access-Control-Request-Headers"));
+            if let Some(allowed) = allowed {
+                headers.push(("Access-Control-Allow-Headers", allowed));
+            }
+        }
+        headers
+            .into_iter()
+            .filter_map(|(name, value)| tiny_http::Header::from_bytes(name, value).ok())
+            .collect()
+    }
+}
+
+/// The headers --cors and --request-id add to responses to `req`.
+fn middleware_headers(
+    req: &tiny_http::Request,
+    options: &HttpOptions,
+    request_id: Option<&Uuid>,
+) -> Vec<tiny_http::Header> {
+    let mut headers = match &options.cors {
+        Some(cors) => cors.headers(req),
+        None => Vec::new(),
+    };
+    if let (true, Some(request_id)) = (options.request_id, request_id) {
+        headers.push(
+            tiny_http::Header::from_bytes("X-Request-Id", request_id.to_string())
+                .unwrap(),
+        );
+    }
+    headers
+}
+
+/// The encoding to compress a response to `req` with, the one it prefers of
+/// those supported.
+fn content_encoding(req: &tiny_http::Request) -> Option<&'static str> {
+    let accepted = header(req, "Accept-Encoding")?;
+    let mut best = ("", 0.0);
+    for coding in accepted.split(',') {
+        let mut parts = coding.split(';').map(|x| x.trim());
+        let name = match parts.next()? {
+            x if x.eq_ignore_ascii_case("gzip") || x.eq_ignore_ascii_case("x-gzip") => {
+                "gzip"
+            }
+            x if x.eq_ignore_ascii_case("deflate") => "deflate",
+            _ => continue,
+        };
+        let q = parts
+            .find_map(|x| x.strip_prefix("q="))
+            .map_or(1.0, |x| x.parse().unwrap_or(0.0));
+        if q > best.1 {
+            best = (name, q);
+        }
+    }
+    (best.1 > 0.0).then_some(best.0)
+}
+
+/// Compresses a body with gzip or deflate.
+fn compress(body: &[u8], encoding: &str) -> io::Result<Vec<u8>> {
+    let level = flate2::Compression::default();
+    if encoding == "gzip" {
+        let mut e = flate2::write::GzEncoder::new(Vec::new(), level);
+        e.write_all(body)?;
+        e.finish()
+    } else {
+        let mut e = flate2::write::ZlibEncoder::new(Vec::new(), level);
+        e.write_all(body)?;
+        e.finish()
+    }
+}
+
+type Requests = Arc<Mutex<HashMap<String, Pending>>>;
+
+/// Requests being handled, and those waiting for their turn.
+#[derive(Default)]
+struct Load {
+    in_flight: usize,
+    queue: VecDeque<tiny_http::Request>,
+    /// the number of requests rejected with a full queue
+    rejected: u64,
+}
+
+/// Bounds the number of requests handled at once, queueing those over the limit.
+struct Limiter {
+    max_in_flight: Option<usize>,
+    max_queue: usize,
+    retry_after: u64,
+    load: Mutex<Load>,
+}
+
+impl Limiter {
+    /// Admits a new request: it's given back if it's to be handled now, and
+    /// otherwise queued. Requests that can't be queued are rejected.
+    fn admit(&self, req: tiny_http::Request) -> Option<tiny_http::Request> {
+        let mut load = self.load.lock().expect("poisoned");
+        match self.max_in_flight {
+            Some(max) if load.in_flight >= max => {
+                if load.queue.len() < self.max_queue {
+                    load.queue.push_back(req);
+                    return None;
+                }
+                load.rejected += 1;
+            }
+            _ => {
+                load.in_flight += 1;
+                return Some(req);
+            }
+        }
+        drop(load);
+
+        let retry_after = self.retry_after.to_string();
+        let response = tiny_http::Response::empty(503).with_header(
+            tiny_http::Header::from_bytes("Retry-After", retry_after).unwrap(),
+        );
+        let access = Access::new(&req);
+        let (status, response_bytes) = respond(req, response);
+        access.log(status, response_bytes);
+        None
+    }
+
+    /// Called as a request is done with: the next queued request, which takes
+    /// over its slot.
+    fn next(&self) -> Option<tiny_http::Request> {
+        let mut load = self.load.lock().expect("poisoned");
+        let next = load.queue.pop_front();
+        if next.is_none() {
+            load.in_flight -= 1;
+        }
+        next
+    }
+}
+
+/// Open WebSocket connections, by connection id, with the frames to send them.
+type Connections = Arc<Mutex<HashMap<String, mpsc::Sender<(u8, Vec<u8>)>>>>;
+
+/// Routes a response, or a WebSocket message, read from STDIN or a worker.
+fn route(line: &str, requests: &Requests, connections: &Connections) {
+    if let Ok(msg) = serde_json::from_str::<WsMessage>(line) {
+        send_ws(connections, msg);
+        return;
+    }
+
+    let res = serde_json::from_str(line);
+    if res.is_err() {
+        println!(
+            "{}",
+            serde_json::json!({
+                "topic": "http.response.log",
+                "content": line,
+                "severity": "ERROR",
+                "error": "unable to parse response",
+            })
+        );
+        return;
+    }
+    let res: Response = res.unwrap();
+
+    let mut requests = requests.lock().expect("poisoned");
+    if let Some(pending) = requests.get_mut(&res.request_id) {
+        pending.streaming |= res.streaming();
+        let done = !pending.streaming || res.end;
+        let request_id = res.request_id.clone();
+        let _ = pending.tx.send(res);
+        if done {
+            requests.remove(&request_id);
+        }
+    } else {
+        log_response(&res, Some("unknown request_id"));
+    }
+}
+
+/// A pool of handler processes, started with --workers, which are written
+/// requests in place of STDOUT, and whose STDOUT is read for responses.
+struct Workers {
+    handler: Vec<String>,
+    /// the id of the pool's first worker: ids are unique across pools
+    first: usize,
+    /// each worker's STDIN, while it's running
+    stdins: Vec<Mutex<Option<process::ChildStdin>>>,
+    /// whether each worker is running, kept apart from its STDIN so picking a
+    /// worker doesn't wait on writes to it
+    running: Vec<AtomicBool>,
+}
+
+impl Workers {
+    fn new(count: usize, handler: Vec<String>) -> Workers {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        Workers {
+            handler,
+            first: NEXT.fetch_add(count, Ordering::Relaxed),
+            stdins: (0..count).map(|_| Mutex::new(None)).collect(),
+            running: (0..count).map(|_| AtomicBool::new(false)).collect(),
+        }
+    }
+
+    fn stdin(&self, worker: usize) -> &Mutex<Option<process::ChildStdin>> {
+        &self.stdins[worker - self.first]
+    }
+
+    /// Starts each of the pool's workers.
+    fn start(self: &Arc<Self>, requests: &Requests, connections: &Connections) {
+        for worker in self.first..self.first + self.stdins.len() {
+            let workers = self.clone();
+            let requests = requests.clone();
+            let connections = connections.clone();
+            thread::spawn(move || run_worker(workers, worker, requests, connections));
+        }
+    }
+
+    /// The running worker with the fewest pending requests.
+    fn pick(&self, requests: &HashMap<String, Pending>) -> usize {
+        let mut load = vec![0; self.stdins.len()];
+        for pending in requests.values() {
+            match pending.worker {
+                Some(worker)
+                    if worker >= self.first && worker - self.first < load.len() =>
+                {
+                    load[worker - self.first] += 1
+                }
+                _ => (),
+            }
+        }
+        let i = (0..self.stdins.len())
+            .min_by_key(|&i| (!self.running[i].load(Ordering::Relaxed), load[i]))
+            .unwrap();
+        self.first + i
+    }
+
+    /// The handler for a new request.
+    fn handler(self: &Arc<Self>, requests: &HashMap<String, Pending>) -> Handler {
+        Handler::Worker(self.clone(), self.pick(requests))
+    }
+}
+
+/// Where a request's packets are written: STDOUT, or the worker handling it.
+#[derive(Clone)]
+enum Handler {
+    Stdout,
+    Worker(Arc<Workers>, usize),
+}
+
+impl Handler {
+    fn send(&self, packet: &serde_json::Value) -> io::Result<()> {
+        match self {
+            Handler::Stdout => {
+                println!("{}", packet);
+                Ok(())
+            }
+            Handler::Worker(workers, worker) => {
+                let mut stdin = workers.stdin(*worker).lock().expect("poisoned");
+                match stdin.as_mut() {
+                    Some(stdin) => writeln!(stdin, "{}", packet),
+                    None => Err(io::ErrorKind::BrokenPipe.into()),
+                }
+            }
+        }
+    }
+
+    fn worker(&self) -> Option<usize> {
+        match self {
+            Handler::Stdout => None,
+            Handler::Worker(_, worker) => Some(*worker),
+        }
+    }
+}
+
+/// Runs a worker, restarting it whenever it exits. Requests still pending on a
+/// worker when it exits are failed with 502 Bad Gateway.
+fn run_worker(
+    workers: Arc<Workers>,
+    worker: usize,
+    requests: Requests,
+    connections: Connections,
+) {
+    loop {
+        let child = process::Command::new(&workers.handler[0])
+            .args(&workers.handler[1..])
+            .stdin(process::Stdio::piped())
+            .stdout(process::Stdio::piped())
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                println!(
+                    "{}",
+                    serde_json::json!({
+                        "topic": "http.worker.exit",
+                        "content": {"worker": worker},
+                        "severity": "ERROR",
+                        "error": format!("unable to start worker: {}", e),
+                    })
+                );
+                thread::sleep(time::Duration::from_secs(1));
+                continue;
+            }
+        };
+
+        *workers.stdin(worker).lock().expect("poisoned") = child.stdin.take();
+        workers.running[worker - workers.first].store(true, Ordering::Relaxed);
+        let buf = BufReader::new(child.stdout.take().unwrap());
+        for line in buf.lines() {
+            match line {
+                Ok(line) => route(&line, &requests, &connections),
+                Err(_) => break,
+            }
+        }
+        workers.running[worker - workers.first].store(false, Ordering::Relaxed);
+        *workers.stdin(worker).lock().expect("poisoned") = None;
+        let status = child.wait().expect("failed to wait on child");
+
+        let failed: Vec<(String, Pending)> = {
+            let mut requests = requests.lock().expect("poisoned");
+            let ids: Vec<String> = requests
+                .iter()
+                .filter(|(_, pending)| pending.worker == Some(worker))
+                .map(|(id, _)| id.clone())
+                .collect();
+            ids.into_iter()
+                .filter_map(|id| requests.remove(&id).map(|pending| (id, pending)))
+                .collect()
+        };
+        println!(
+            "{}",
+            serde_json::json!({
+                "topic": "http.worker.exit",
+                "content": {
+                    "worker": worker,
+                    "code": status.code(),
+                    "failed": failed.len(),
+                },
+                "severity": "ERROR",
+            })
+        );
+        for (request_id, pending) in failed {
+            let _ = pending.tx.send(Response {
+                request_id,
+                status: Some(502),
+                end: true,
+                ..Default::default()
+            });
+        }
+
+        // don't spin when a worker fails on start
+        thread::sleep(time::Duration::from_millis(100));
+    }
+}
+
+fn run_http(sock: net::SocketAddr, options: HttpOptions) -> Result<()> {
+    let options = Arc::new(options);
+
+    let requests: HashMap<String, Pending> = HashMap::new();
+    let requests = Arc::new(Mutex::new(requests));
+    let connections: Connections = Arc::new(Mutex::new(HashMap::new()));
+
+    // shut down on SIGTERM, and once STDIN is closed, unless responses come from
+    // handler processes or an upstream server in place of STDIN
+    let terminate = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGTERM, terminate.clone())?;
+    let closed = Arc::new(AtomicBool::new(false));
+    {
+        let requests = requests.clone();
+        let connections = connections.clone();
+        let closed = closed.clone();
+        let stdin_responds = options.workers.is_none()
+            && options.cgi.is_none()
+            && options.proxy.is_none();
+        thread::spawn(move || {
+            let stdin = io::stdin();
+            let buf = BufReader::new(stdin);
+            for line in buf.lines() {
+                route(&line.unwrap(), &requests, &connections);
+            }
+            closed.store(stdin_responds, Ordering::Relaxed);
+        });
+    }
+
+    if let Some(workers) = &options.workers {
+        workers.start(&requests, &connections);
+    }
+    for route in &options.routes {
+        if let Destination::Workers(workers) = &route.destination {
+            workers.start(&requests, &connections);
+        }
+    }
+
+    let limiter = Arc::new(Limiter {
+        max_in_flight: options.max_in_flight,
+        max_queue: options.max_queue,
+        retry_after: options.retry_after,
+        load: Mutex::new(Load::default()),
+    });
+    let mut metrics_at = time::Instant::now();
+
+    let mut tls = load_tls(&options)?;
+    // servers are swapped on the listener when certificates are reloaded: it stays
+    // open throughout, so connections aren't refused meanwhile
+    let listener = net::TcpListener::bind(sock)?;
+    let mut server = serve(&listener, &tls)?;
+
+    // reload certificates on SIGHUP
+    let reload = Arc::new(AtomicBool::new(false));
+    if options.tls.is_some() {
+        signal_hook::flag::register(signal_hook::consts::SIGHUP, reload.clone())?;
+    }
+
+    loop {
+        let reason = if terminate.load(Ordering::Relaxed) {
+            Some("SIGTERM")
+        } else if closed.load(Ordering::Relaxed) {
+            Some("EOF")
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            // stop accepting requests
+            drop(server);
+            drop(listener);
+            shutdown(reason, &requests, &connections, &limiter, &options);
+            return Ok(());
+        }
+
+        if reload.swap(false, Ordering::Relaxed) {
+            let reloaded = match load_tls(&options) {
+                Ok(reloaded) => {
+                    // the previous server stops accepting before the next starts,
+                    // so that it's the one woken to stop
+                    drop(server);
+                    match serve(&listener, &reloaded) {
+                        Ok(reloaded_server) => {
+                            server = reloaded_server;
+                            tls = reloaded;
+                            Ok(())
+                        }
+                        Err(e) => {
+                            server = serve_again(&listener, &tls);
+                            Err(e)
+                        }
+                    }
+                }
+                Err(e) => Err(e),
+            };
+            let packet = match reloaded {
+                Ok(()) => serde_json::json!({
+                    "topic": "http.tls.reload",
+                    "severity": "INFO",
+                }),
+                Err(e) => serde_json::json!({
+                    "topic": "http.tls.reload",
+                    "severity": "ERROR",
+                    "error": format!("{:#}", e),
+                }),
+            };
+            println!("{}", packet);
+        }
+
+        if let Some(interval) = options.metrics {
+            if metrics_at.elapsed() >= interval {
+                metrics_at = time::Instant::now();
+                let load = limiter.load.lock().expect("poisoned");
+                println!(
+                    "{}",
+                    serde_json::json!({
+                        "topic": "http.metrics",
+                        "content": {
+                            "in_flight": load.in_flight,
+                            "queued": load.queue.len(),
+                            "rejected": load.rejected,
+                            "pending": requests.lock().expect("poisoned").len(),
+                            "connections": connections.lock().expect("poisoned").len(),
+                        },
+                    })
+                );
+            }
+        }
+
+        let req = match server.recv_timeout(time::Duration::from_millis(100))? {
+            Some(req) => req,
+            None => continue,
+        };
+        let req = match limiter.admit(req) {
+            Some(req) => req,
+            None => continue,
+        };
+
+        let slot = Slot {
+            limiter: limiter.clone(),
+            requests: requests.clone(),
+            connections: connections.clone(),
+            options: options.clone(),
+        };
+        slot.spawn(req);
+    }
+}
+
+/// A request's slot in the limiter, held while it's handled. Dropping it, even
+/// as a handler panics, passes it on to the next queued request.
+struct Slot {
+    limiter: Arc<Limiter>,
+    requests: Requests,
+    connections: Connections,
+    options: Arc<HttpOptions>,
+}
+
+impl Slot {
+    /// Handles `req` on a new thread, releasing the slot once it's done.
+    fn spawn(self, req: tiny_http::Request) {
+        thread::spawn(move || {
+            let slot = self;
+            handle_http(
+                req,
+                slot.requests.clone(),
+                slot.connections.clone(),
+                slot.options.clone(),
+            );
+        });
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let Some(req) = self.limiter.next() {
+            let slot = Slot {
+                limiter: self.limiter.clone(),
+                requests: self.requests.clone(),
+                connections: self.connections.clone(),
+                options: self.options.clone(),
+            };
+            slot.spawn(req);
+        }
+    }
+}
+
+/// Shuts the server down, once it's stopped accepting requests: WebSockets are
+/// closed, and requests being handled are given `shutdown_timeout` to finish.
+/// Those still pending after that are responded to with 503 Service Unavailable.
+fn shutdown(
+    reason: &str,
+    requests: &Requests,
+    connections: &Connections,
+    limiter: &Limiter,
+    options: &HttpOptions,
+) {
+    let idle = |timeout: time::Duration| {
+        let start = time::Instant::now();
+        while limiter.load.lock().expect("poisoned").in_flight > 0 {
+            if start.elapsed() >= timeout {
+                return false;
+            }
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        true
+    };
+
+    // 1001 Going Away
+    let closed: Vec<_> = connections.lock().expect("poisoned").drain().collect();
+    for (_, tx) in &closed {
+        let _ = tx.send((WS_CLOSE, 1001u16.to_be_bytes().to_vec()));
+    }
+
+    let mut abandoned = 0;
+    if !idle(options.shutdown_timeout) {
+        let pending: Vec<_> = requests.lock().expect("poisoned").drain().collect();
+        abandoned = pending.len();
+        for (request_id, pending) in pending {
+            let _ = pending.tx.send(Response {
+                request_id,
+                status: Some(503),
+                end: true,
+                ..Default::default()
+            });
+        }
+        // give the 503s a moment to be written
+        idle(time::Duration::from_millis(1000));
+    }
+
+    println!(
+        "{}",
+        serde_json::json!({
+            "topic": "http.shutdown",
+            "content": {
+                "reason": reason,
+                "closed": closed.len(),
+                "abandoned": abandoned,
+            },
+            "severity": if abandoned > 0 { "ERROR" } else { "INFO" },
+        })
+    );
+}
+
+/// Reads the certificate and private key to serve HTTPS with, if configured.
+fn load_tls(options: &HttpOptions) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let (cert, key) = match options.tls {
+        Some((ref cert, ref key)) => (cert, key),
+        None => return Ok(None),
+    };
+    let cert = fs::read(cert)
+        .with_context(|| format!("could not read certificate `{}`", cert.display()))?;
+    let key = fs::read(key)
+        .with_context(|| format!("could not read private key `{}`", key.display()))?;
+    Ok(Some((cert, key)))
+}
+
+fn serve(
+    listener: &net::TcpListener,
+    tls: &Option<(Vec<u8>, Vec<u8>)>,
+) -> Result<tiny_http::Server> {
+    let ssl = tls.as_ref().map(|(cert, key)| tiny_http::SslConfig {
+        certificate: cert.clone(),
+        private_key: key.clone(),
+    });
+    tiny_http::Server::from_listener(listener.try_clone()?, ssl).map_err(|e| {
+        match e.downcast::<io::Error>() {
+            Ok(e) => anyhow::Error::from(*e),
+            Err(e) => anyhow::anyhow!(e),
+        }
+    })
+}
+
+/// Serves again with certificates that have been served before, after failing to
+/// serve with new ones. Whatever failed is retried rather than exiting.
+fn serve_again(
+    listener: &net::TcpListener,
+    tls: &Option<(Vec<u8>, Vec<u8>)>,
+) -> tiny_http::Server {
+    loop {
+        match serve(listener, tls) {
+            Ok(server) => return server,
+            Err(_) => thread::sleep(time::Duration::from_millis(100)),
+        }
+    }
+}
+
+fn headers(req: &tiny_http::Request) -> Vec<(String, String)> {
+    // gosh, this is terrible. I need to get better with rust's type system
+    req.headers()
+        .iter()
+        .map(|x| (format!("{}", x.field.as_str()), format!("{}", x.value)))
+        .collect()
+}
+
+/// The value of the first header with the given name.
+fn header<'a>(req: &'a tiny_http::Request, name: &'static str) -> Option<&'a str> {
+    req.headers()
+        .iter()
+        .find(|x| x.field.equiv(name))
+        .map(|x| x.value.as_str())
+}
+
+/// The request's URL resolved against its Host header, without a fragment and
+/// with dot segments removed.
+fn normalize_url(req: &tiny_http::Request) -> url::Url {
+    let scheme = if req.secure() { "https" } else { "http" };
+    let host = header(req, "Host").unwrap_or("localhost");
+    let base =
+        url::Url::parse(&format!("{}://{}/", scheme, host)).unwrap_or_else(|_| {
+            url::Url::parse(&format!("{}://localhost/", scheme)).unwrap()
+        });
+    let mut url = base.join(req.url()).unwrap_or(base);
+    url.set_fragment(None);
+    url
+}
+
+/// Decoded query parameters; repeated keys keep every value in order.
+fn query_params(url: &url::Url) -> HashMap<String, Vec<String>> {
+    let mut query: HashMap<String, Vec<String>> = HashMap::new();
+    for (key, value) in url.query_pairs() {
+        query
+            .entry(key.into_owned())
+            .or_default()
+            .push(value.into_owned());
+    }
+    query
+}
+
+/// Decoded cookies from every Cookie header; the first value of a repeated name
+/// wins, as it's the most specific.
+fn cookies(req: &tiny_http::Request) -> HashMap<String, String> {
+    let mut cookies = HashMap::new();
+    for header in req.headers().iter().filter(|x| x.field.equiv("Cookie")) {
+        for pair in header.value.as_str().split(';') {
+            if let Some((name, value)) = pair.split_once('=') {
+                let value = value.trim().trim_matches('"');
+                let value =
+                    percent_encoding::percent_decode_str(value).decode_utf8_lossy();
+                cookies
+                    .entry(name.trim().to_string())
+                    .or_insert_with(|| value.into_owned());
+            }
+        }
+    }
+    cookies
+}
+
+/// Handles a request, however it's responded to, then logs the response.
+fn handle_http(
+    req: tiny_http::Request,
+    requests: Requests,
+    connections: Connections,
+    options: Arc<HttpOptions>,
+) {
+    let mut access = Access::new(&req);
+    let (status, response_bytes) =
+        serve_http(req, &mut access, requests, connections, options);
+    access.log(status, response_bytes);
+}
+
+/// Responds to a request, returning the status and length of the response.
+fn serve_http(
+    mut req: tiny_http::Request,
+    access: &mut Access,
+    requests: Requests,
+    connections: Connections,
+    options: Arc<HttpOptions>,
+) -> (u16, usize) {
+    if options.cors.is_some() && Cors::preflight(&req) {
+        let mut response = tiny_http::Response::empty(204);
+        for header in middleware_headers(&req, &options, None) {
+            response.add_header(header);
+        }
+        return respond(req, response);
+    }
+
+    // requests without valid credentials go no further
+    let principal = match &options.auth {
+        Some(auth) => match auth.principal(&req) {
+            Some(principal) => Some(principal),
+            None => {
+                let mut response = auth.unauthorized();
+                for header in middleware_headers(&req, &options, None) {
+                    response.add_header(header);
+                }
+                return respond(req, response);
+            }
+        },
+        None => None,
+    };
+
+    if let Some(key) = websocket_key(&req) {
+        if options.proxy.is_some() {
+            return respond(req, tiny_http::Response::empty(501));
+        }
+        return handle_ws(req, &key, principal, connections, &requests, options);
+    }
+
+    let url = normalize_url(&req);
+    let path = percent_encoding::percent_decode_str(url.path())
+        .decode_utf8_lossy()
+        .to_string();
+
+    // requests matching a route go to its destination, the rest to --static,
+    // --cgi, --workers or STDOUT
+    let mut topic = "http.request";
+    let mut workers = options.workers.as_ref();
+    let mut params = HashMap::new();
+    let route = options.routes.iter().find_map(|route| {
+        let (params, rest) = route.matches(req.method(), &path)?;
+        Some((&route.destination, params, rest))
+    });
+    match route {
+        Some((destination, matched, rest)) => {
+            params = matched;
+            match destination {
+                Destination::Topic(name) => topic = name,
+                Destination::Workers(route_workers) => workers = Some(route_workers),
+                Destination::Cgi(command) => {
+                    return handle_cgi(req, command, principal, &options);
+                }
+                Destination::Static(dir) => {
+                    // only files are served, as with --static
+                    if !matches!(
+                        req.method(),
+                        tiny_http::Method::Get | tiny_http::Method::Head
+                    ) {
+                        let allow = tiny_http::Header::from_bytes("Allow", "GET, HEAD");
+                        let response = tiny_http::Response::empty(405);
+                        return respond(req, response.with_header(allow.unwrap()));
+                    }
+                    return match static_path(dir, rest.as_deref().unwrap_or(&path)) {
+                        Some(file) => respond_static(req, &file, &options),
+                        None => respond(req, tiny_http::Response::empty(404)),
+                    };
+                }
+            }
+        }
+        None => {
+            if let Some((dir, prefix)) = &options.static_files {
+                if let Some(file) = static_file(&req, dir, prefix) {
+                    return respond_static(req, &file, &options);
+                }
+            }
+            if let Some(command) = &options.cgi {
+                return handle_cgi(req, command, principal, &options);
+            }
+        }
+    }
+
+    let uid = access.request_id;
+    // requests for STDOUT go to the upstream server instead, with --proxy
+    let proxy = options.proxy.as_ref().filter(|_| workers.is_none());
+
+    if let (Some(max), Some(length)) = (options.max_body, req.body_length()) {
+        if length > max {
+            return respond(req, tiny_http::Response::empty(413));
+        }
+    }
+
+    // bodies larger than the chunk size, or of unknown length, are streamed as
+    // http.request.chunk packets following the request, unless they're forwarded
+    let stream = match options.chunk_size {
+        Some(_) if proxy.is_some() => false,
+        Some(size) => match req.body_length() {
+            Some(length) => length > size,
+            None => req
+                .headers()
+                .iter()
+                .any(|x| x.field.equiv("Transfer-Encoding")),
+        },
+        None => false,
+    };
+
+    let body = if stream {
+        None
+    } else {
+        let mut body = Vec::new();
+        let limit = options.max_body.map_or(u64::MAX, |max| max as u64 + 1);
+        if req.as_reader().take(limit).read_to_end(&mut body).is_err() {
+            return respond(req, tiny_http::Response::empty(400));
+        }
+        if matches!(options.max_body, Some(max) if body.len() > max) {
+            return respond(req, tiny_http::Response::empty(413));
+        }
+        access.request_bytes = body.len();
+        Some(body)
+    };
+
+    let headers = headers(&req);
+    let packet = serde_json::json!({
+        "topic": topic,
+        "content": {
+            "method": req.method().as_str(),
+            "headers": headers,
+            "remote_addr": req.remote_addr(),
+            "url": req.url(),
+            "normalized_url": url.as_str(),
+            "path": path,
+            "query": query_params(&url),
+            "cookies": cookies(&req),
+            "params": params,
+            "principal": principal,
+            "body": body.as_ref().map(|x| base64::encode_config(x, base64::URL_SAFE)),
+            "stream": stream,
+            "request_id": uid,
+        },
+    });
+
+    if let Some(proxy) = proxy {
+        println!("{}", packet);
+        access.sent = time::Instant::now();
+        let forwarded = proxy.forward(&req, &uid, &body.unwrap_or_default());
+        access.latency = Some(access.sent.elapsed());
+
+        let res = match forwarded {
+            Ok(res) => res,
+            Err(error) => {
+                println!(
+                    "{}",
+                    serde_json::json!({
+                        "topic": "http.proxy.response",
+                        "content": {"request_id": uid},
+                        "severity": "ERROR",
+                        "error": error,
+                    })
+                );
+                return respond(req, tiny_http::Response::empty(502));
+            }
+        };
+        println!(
+            "{}",
+            serde_json::json!({"topic": "http.proxy.response", "content": res})
+        );
+
+        let status = res.status.unwrap_or(200);
+        let body = res.decode_body().unwrap();
+        let mut http_response =
+            tiny_http::Response::from_data(body).with_status_code(status);
+        for (key, value) in res.headers.unwrap_or_default() {
+            if let Ok(header) = tiny_http::Header::from_bytes(key, value) {
+                http_response.add_header(header);
+            }
+        }
+        for header in middleware_headers(&req, &options, Some(&uid)) {
+            http_response.add_header(header);
+        }
+        return respond(req, http_response);
+    }
+
+    let (tx, rx) = mpsc::channel();
+
+    let handler = {
+        let mut requests = requests.lock().expect("poisoned");
+        let handler = match workers {
+            Some(workers) => workers.handler(&requests),
+            None => Handler::Stdout,
+        };
+        requests.insert(
+            uid.to_string(),
+            Pending {
+                tx,
+                streaming: false,
+                worker: handler.worker(),
+            },
+        );
+        handler
+    };
+    access.sent = time::Instant::now();
+    if handler.send(&packet).is_err() {
+        requests.lock().expect("poisoned").remove(&uid.to_string());
+        return respond(req, tiny_http::Response::empty(502));
+    }
+
+    if stream {
+        let size = options.chunk_size.unwrap();
+        let mut read = 0;
+        loop {
+            let mut chunk = Vec::new();
+            let error = match req.as_reader().take(size as u64).read_to_end(&mut chunk) {
+                Ok(_) => {
+                    read += chunk.len();
+                    match options.max_body {
+                        Some(max) if read > max => Some(413),
+                        _ => None,
+                    }
+                }
+                Err(_) => Some(400),
+            };
+            let end = error.is_some() || chunk.len() < size;
+            access.request_bytes = read;
+
+            let _ = handler.send(&serde_json::json!({
+                "topic": format!("{}.chunk", topic),
+                "content": {
+                    "request_id": uid,
+                    "body": base64::encode_config(chunk, base64::URL_SAFE),
+                    "end": end,
+                    "error": error.map(|_| "unable to read body"),
+                },
+            }));
+
+            if let Some(status) = error {
+                requests.lock().expect("poisoned").remove(&uid.to_string());
+                return respond(req, tiny_http::Response::empty(status));
+            }
+            if end {
+                break;
+            }
+        }
+    }
+
+    let res = match options.timeout {
+        None => rx.recv().unwrap(),
+        Some(timeout) => match rx.recv_timeout(timeout) {
+            Ok(res) => res,
+            Err(_) => {
+                let removed = requests
+                    .lock()
+                    .expect("poisoned")
+                    .remove(&uid.to_string())
+                    .is_some();
+                // otherwise, the response arrived as we timed out
+                if removed {
+                    println!(
+                        "{}",
+                        serde_json::json!({
+                            "topic": "http.request.timeout",
+                            "content": {
+                                "request_id": uid,
+                                "timeout": timeout.as_millis() as u64,
+                            },
+                        })
+                    );
+                    return respond(req, tiny_http::Response::empty(504));
+                }
+                rx.recv().unwrap()
+            }
+        },
+    };
+    access.latency = Some(access.sent.elapsed());
+
+    let status = res.status.unwrap_or(200);
+    let valid_headers = res
+        .headers()
+        .iter()
+        .all(|(key, value)| tiny_http::Header::from_bytes(&key[..], &value[..]).is_ok());
+    let body = if !(100..=599).contains(&status) {
+        Err("invalid status")
+    } else if matches!(&res.reason, Some(reason) if reason.bytes().any(|b| b.is_ascii_control()))
+    {
+        Err("invalid reason")
+    } else if !valid_headers {
+        Err("invalid header")
+    } else {
+        res.decode_body()
+    };
+    let mut body = match body {
+        Ok(body) => body,
+        Err(error) => {
+            log_response(&res, Some(error));
+            return respond(req, tiny_http::Response::empty(500));
+        }
+    };
+    log_response(&res, None);
+
+    if res.streaming() {
+        if res.event_stream || req.http_version() != &tiny_http::HTTPVersion(1, 0) {
+            let mut written = 0;
+            let keep_alive = options.keep_alive;
+            let extra = middleware_headers(&req, &options, Some(&uid));
+            if respond_streamed(req, &res, body, &rx, keep_alive, extra, &mut written)
+                .is_err()
+            {
+                // the client went away: further packets for it are unknown
+                requests.lock().expect("poisoned").remove(&uid.to_string());
+            }
+            return (status, written);
+        }
+        // HTTP/1.0 clients don't support chunked transfer encoding: collect
+        // the whole body instead
+        let mut end = res.end;
+        while !end {
+            let res = match rx.recv() {
+                Ok(res) => res,
+                Err(_) => break,
+            };
+            match res.decode_body() {
+                Ok(chunk) => {
+                    log_response(&res, None);
+                    body.extend(chunk);
+                }
+                Err(error) => log_response(&res, Some(error)),
+            }
+            end = res.end;
+        }
+    }
+
+    let headers = res.headers();
+    let encoded = headers
+        .iter()
+        .any(|(key, _)| key.eq_ignore_ascii_case("Content-Encoding"));
+    let mut encoding = None;
+    if options.compress && !encoded && !body.is_empty() {
+        if let Some(name) = content_encoding(&req) {
+            body = compress(&body, name).unwrap();
+            encoding = Some(name);
+        }
+    }
+
+    let mut http_response =
+        tiny_http::Response::from_data(body).with_status_code(status);
+    for (key, value) in headers {
+        if let Ok(add) = tiny_http::Header::from_bytes(key, value) {
+            http_response = http_response.with_header(add);
+        }
+    }
+    if let Some(encoding) = encoding {
+        http_response.add_header(
+            tiny_http::Header::from_bytes("Content-Encoding", encoding).unwrap(),
+        );
+        http_response.add_header(
+            tiny_http::Header::from_bytes("Vary", "Accept-Encoding").unwrap(),
+        );
+    }
+    for header in middleware_headers(&req, &options, Some(&uid)) {
+        http_response.add_header(header);
+    }
+
+    match res.reason.as_deref() {
+        Some(reason) => respond_with_reason(req, http_response, reason),
+        None => respond(req, http_response),
+    }
+}
+
+/// Responds to `req`, returning the status and length of the response.
+fn respond<R: Read>(
+    req: tiny_http::Request,
+    response: tiny_http::Response<R>,
+) -> (u16, usize) {
+    let status = response.status_code().0;
+    let length = response.data_length().unwrap_or(0);
+    let _ = req.respond(response);
+    (status, length)
+}
+
+/// Responds to `req` with `reason` as the status line's reason phrase: tiny_http
+/// always writes the status's standard one, so its output is rewritten.
+fn respond_with_reason<R: Read>(
+    req: tiny_http::Request,
+    response: tiny_http::Response<R>,
+    reason: &str,
+) -> (u16, usize) {
+    let status = response.status_code().0;
+    let length = response.data_length().unwrap_or(0);
+    let mut raw = Vec::new();
+    let head = req.method() == &tiny_http::Method::Head;
+    let version = req.http_version().clone();
+    if response
+        .raw_print(&mut raw, version, req.headers(), head, None)
+        .is_err()
+    {
+        return (500, 0);
+    }
+    let line = raw.windows(2).position(|x| x == b"\r\n").unwrap_or(0);
+    let protocol = raw.iter().position(|&b| b == b' ').unwrap_or(0);
+    let mut w = req.into_writer();
+    let _ = w
+        .write_all(&raw[..protocol])
+        .and_then(|_| write!(w, " {} {}", status, reason))
+        .and_then(|_| w.write_all(&raw[line..]))
+        .and_then(|_| w.flush());
+    (status, length)
+}
+
+/// The file to serve for a GET or HEAD request, if it's for a path under `prefix`
+/// that exists in `dir`.
+fn static_file(req: &tiny_http::Request, dir: &Path, prefix: &str) -> Option<PathBuf> {
+    if !matches!(
+        req.method(),
+        tiny_http::Method::Get | tiny_http::Method::Head
+    ) {
+        return None;
+    }
+    let url = normalize_url(req);
+    let path = percent_encoding::percent_decode_str(url.path())
+        .decode_utf8()
+        .ok()?;
+    let rest = path.strip_prefix(prefix)?;
+    if !rest.is_empty() && !rest.starts_with('/') {
+        return None;
+    }
+    static_path(dir, rest)
+}
+
+/// The file in `dir` at the URL path `rest`, if it exists. Directories are served
+/// their index.html.
+fn static_path(dir: &Path, rest: &str) -> Option<PathBuf> {
+    let mut file = dir.to_path_buf();
+    for component in rest.split('/').filter(|x| !x.is_empty()) {
+        if component == ".." || component.contains(&['\\', '\0'][..]) {
+            return None;
+        }
+        file.push(component);
+    }
+    if file.is_dir() {
+        file.push("index.html");
+    }
+    file.is_file().then_some(file)
+}
+
+/// Parses a Range header for a file of the given length. Only single byte ranges
+/// are supported: `Ok(None)` means the whole file should be served, and `Err(())`
+/// that the range can't be satisfied.
+fn byte_range(range: &str, length: u64) -> Result<Option<(u64, u64)>, ()> {
+    let spec = match range.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Ok(None),
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(range) => range,
+        None => return Ok(None),
+    };
+    let last = length.saturating_sub(1);
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(last)),
+        (Ok(start), Err(_)) if end.is_empty() => (start, last),
+        (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => {
+            (length.saturating_sub(suffix), last)
+        }
+        _ => return Ok(None),
+    };
+    if start >= length {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+fn respond_static(
+    req: tiny_http::Request,
+    path: &Path,
+    options: &HttpOptions,
+) -> (u16, usize) {
+    let opened = fs::File::open(path).and_then(|file| {
+        let metadata = file.metadata()?;
+        Ok((file, metadata.len(), metadata.modified()?))
+    });
+    let (mut file, length, modified) = match opened {
+        Ok(opened) => opened,
+        Err(_) => return respond(req, tiny_http::Response::empty(500)),
+    };
+    let modified = modified
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let etag = format!("\"{:x}-{:x}\"", length, modified.as_nanos());
+
+    let mut headers = vec![
+        tiny_http::Header::from_bytes("ETag", etag.as_bytes()).unwrap(),
+        tiny_http::Header::from_bytes("Accept-Ranges", "bytes").unwrap(),
+    ];
+    headers.extend(middleware_headers(&req, options, None));
+
+    if let Some(tags) = header(&req, "If-None-Match") {
+        let matched = tags
+            .split(',')
+            .map(|x| x.trim())
+            .any(|x| x == "*" || x.strip_prefix("W/").unwrap_or(x) == etag);
+        if matched {
+            let mut response = tiny_http::Response::empty(304);
+            for header in headers {
+                response.add_header(header);
+            }
+            return respond(req, response);
+        }
+    }
+
+    let range = header(&req, "Range").map_or(Ok(None), |x| byte_range(x, length));
+    let (status, start, count) = match range {
+        Ok(None) => (200, 0, length),
+        Ok(Some((start, end))) => {
+            let content_range = format!("bytes {}-{}/{}", start, end, length);
+            headers.push(
+                tiny_http::Header::from_bytes("Content-Range", content_range).unwrap(),
+            );
+            (206, start, end - start + 1)
+        }
+        Err(()) => {
+            let content_range = format!("bytes */{}", length);
+            let response = tiny_http::Response::empty(416).with_header(
+                tiny_http::Header::from_bytes("Content-Range", content_range).unwrap(),
+            );
+            return respond(req, response);
+        }
+    };
+
+    let content_type = mime_guess::from_path(path).first_or_octet_stream();
+    headers.push(
+        tiny_http::Header::from_bytes("Content-Type", content_type.as_ref()).unwrap(),
+    );
+    if file.seek(io::SeekFrom::Start(start)).is_err() {
+        return respond(req, tiny_http::Response::empty(500));
+    }
+    let response = tiny_http::Response::new(
+        tiny_http::StatusCode(status),
+        headers,
+        file.take(count),
+        Some(count as usize),
+        None,
+    );
+    respond(req, response)
+}
+
+/// Turns a CGI process's output into a response. The header block is optional:
+/// output that doesn't start with one is all body.
+fn parse_cgi(output: Vec<u8>) -> Option<tiny_http::Response<io::Cursor<Vec<u8>>>> {
+    let mut headers = Vec::new();
+    let mut start = 0;
+    let body = loop {
+        let end = match output[start..].iter().position(|b| *b == b'\n') {
+            Some(end) => start + end,
+            None => {
+                headers.clear();
+                break output;
+            }
+        };
+        let line = &output[start..end];
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            break output[end + 1..].to_vec();
+        }
+        let header = std::str::from_utf8(line)
+            .ok()
+            .and_then(|line| line.split_once(':'))
+            .filter(|(name, _)| {
+                !name.is_empty()
+                    && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+            });
+        match header {
+            Some((name, value)) => {
+                headers.push((name.to_string(), value.trim().to_string()))
+            }
+            None => {
+                headers.clear();
+                break output;
+            }
+        }
+        start = end + 1;
+    };
+
+    let mut status = if headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("Location"))
+    {
+        302
+    } else {
+        200
+    };
+    if let Some(i) = headers
+        .iter()
+        .position(|(name, _)| name.eq_ignore_ascii_case("Status"))
+    {
+        let (_, value) = headers.remove(i);
+        status = value.split(' ').next()?.parse().ok()?;
+    }
+    if !(100..=599).contains(&status) {
+        return None;
+    }
+
+    let mut response = tiny_http::Response::from_data(body).with_status_code(status);
+    if !headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("Content-Type"))
+    {
+        headers.insert(
+            0,
+            (
+                "Content-Type".to_string(),
+                "text/html; charset=utf8".to_string(),
+            ),
+        );
+    }
+    for (name, value) in headers {
+        if let Ok(header) = tiny_http::Header::from_bytes(name, value) {
+            response.add_header(header);
+        }
+    }
+    Some(response)
+}
+
+/// Handles a request with its own process, CGI style. Processes that fail are
+/// responded to with 502 Bad Gateway, and those that time out are killed.
+fn handle_cgi(
+    mut req: tiny_http::Request,
+    command: &[String],
+    principal: Option<String>,
+    options: &HttpOptions,
+) -> (u16, usize) {
+    let mut body = Vec::new();
+    let limit = options.max_body.map_or(u64::MAX, |max| max as u64 + 1);
+    if req.as_reader().take(limit).read_to_end(&mut body).is_err() {
+        return respond(req, tiny_http::Response::empty(400));
+    }
+    if matches!(options.max_body, Some(max) if body.len() > max) {
+        return respond(req, tiny_http::Response::empty(413));
+    }
+
+    let uri = req.url().to_string();
+    let url = normalize_url(&req);
+    let mut cmd = process::Command::new(&command[0]);
+    cmd.args(&command[1..])
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .env("GATEWAY_INTERFACE", "CGI/1.1")
+        .env("SERVER_PROTOCOL", format!("HTTP/{}", req.http_version()))
+        .env("REQUEST_METHOD", req.method().as_str())
+        .env("REQUEST_URI", &uri)
+        .env(
+            "PATH_INFO",
+            percent_encoding::percent_decode_str(url.path())
+                .decode_utf8_lossy()
+                .as_ref(),
+        )
+        .env("QUERY_STRING", url.query().unwrap_or(""))
+        .env("CONTENT_LENGTH", body.len().to_string());
+    let addr = req.remote_addr();
+    cmd.env("REMOTE_ADDR", addr.ip().to_string())
+        .env("REMOTE_PORT", addr.port().to_string());
+    if let Some(principal) = principal {
+        cmd.env("REMOTE_USER", principal);
+    }
+    for (name, value) in headers(&req) {
+        let name = name.to_ascii_uppercase().replace('-', "_");
+        match name.as_str() {
+            "CONTENT_TYPE" => cmd.env(name, value),
+            "CONTENT_LENGTH" => continue,
+            // HTTP_PROXY would set the process's proxy (httpoxy)
+            "PROXY" => continue,
+            _ => cmd.env(format!("HTTP_{}", name), value),
+        };
+    }
+
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            println!(
+                "{}",
+                serde_json::json!({
+                    "topic": "http.cgi.exit",
+                    "content": {"url": uri},
+                    "severity": "ERROR",
+                    "error": format!("unable to start command: {}", e),
+                })
+            );
+            return respond(req, tiny_http::Response::empty(500));
+        }
+    };
+
+    let mut stdin = child.stdin.take().unwrap();
+    thread::spawn(move || stdin.write_all(&body));
+    let mut stdout = child.stdout.take().unwrap();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        let _ = stdout.read_to_end(&mut output);
+        let _ = tx.send(output);
+    });
+
+    let output = match options.timeout {
+        None => rx.recv().unwrap(),
+        Some(timeout) => match rx.recv_timeout(timeout) {
+            Ok(output) => output,
+            Err(_) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                println!(
+                    "{}",
+                    serde_json::json!({
+                        "topic": "http.cgi.exit",
+                        "content": {
+                            "url": uri,
+                            "timeout": timeout.as_millis() as u64,
+                        },
+                        "severity": "ERROR",
+                        "error": "timed out",
+                    })
+                );
+                return respond(req, tiny_http::Response::empty(504));
+            }
+        },
+    };
+    let status = child.wait().expect("failed to wait on child");
+
+    let response = if status.success() {
+        parse_cgi(output).ok_or("invalid status")
+    } else {
+        Err("command failed")
+    };
+    match response {
+        Ok(mut response) => {
+            for header in middleware_headers(&req, options, None) {
+                response.add_header(header);
+            }
+            respond(req, response)
+        }
+        Err(error) => {
+            println!(
+                "{}",
+                serde_json::json!({
+                    "topic": "http.cgi.exit",
+                    "content": {"url": uri, "code": status.code()},
+                    "severity": "ERROR",
+                    "error": error,
+                })
+            );
+            respond(req, tiny_http::Response::empty(502))
+        }
+    }
+}
+
+const WS_CONTINUATION: u8 = 0x0;
+const WS_TEXT: u8 = 0x1;
+const WS_BINARY: u8 = 0x2;
+const WS_CLOSE: u8 = 0x8;
+const WS_PING: u8 = 0x9;
+const WS_PONG: u8 = 0xa;
+
+/// How long a WebSocket connection first sends frames for before pinging the
+/// client to read what it's sent. The wait doubles each time the client's sent
+/// nothing but the pong, up to --keep-alive.
+const WS_POLL: time::Duration = time::Duration::from_millis(100);
+
+/// The Sec-WebSocket-Key of a WebSocket upgrade request.
+fn websocket_key(req: &tiny_http::Request) -> Option<String> {
+    if !header(req, "Upgrade")?
+        .trim()
+        .eq_ignore_ascii_case("websocket")
+    {
+        return None;
+    }
+    header(req, "Sec-WebSocket-Key").map(|x| x.trim().to_string())
+}
+
+fn log_ws(msg: &WsMessage, error: &str) {
+    println!(
+        "{}",
+        serde_json::json!({
+            "topic": "http.ws.log",
+            "content": msg,
+            "severity": "ERROR",
+            "error": error,
+        })
+    );
+}
+
+/// Sends a message read from STDIN to its connection.
+fn send_ws(connections: &Connections, msg: WsMessage) {
+    let mut connections = connections.lock().expect("poisoned");
+    let tx = match connections.get(&msg.connection_id) {
+        Some(tx) => tx.clone(),
+        None => return log_ws(&msg, "unknown connection_id"),
+    };
+    let frame = if msg.close {
+        connections.remove(&msg.connection_id);
+        (WS_CLOSE, 1000u16.to_be_bytes().to_vec())
+    } else {
+        match decode_body(&msg.body, msg.encoding.as_deref()) {
+            Ok(body) if msg.encoding.as_deref() == Some("base64") => (WS_BINARY, body),
+            Ok(body) => (WS_TEXT, body),
+            Err(error) => return log_ws(&msg, error),
+        }
+    };
+    let _ = tx.send(frame);
+}
+
+/// A frame's header: whether it's the last of its message, its opcode, its
+/// mask, if the payload is masked, and the payload's length.
+fn read_head<R: Read + ?Sized>(
+    r: &mut R,
+) -> io::Result<(bool, u8, Option<[u8; 4]>, u64)> {
+    let mut head = [0u8; 2];
+    r.read_exact(&mut head)?;
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0f;
+    let length = match head[1] & 0x7f {
+        126 => {
+            let mut length = [0u8; 2];
+            r.read_exact(&mut length)?;
+            u16::from_be_bytes(length) as u64
+        }
+        127 => {
+            let mut length = [0u8; 8];
+            r.read_exact(&mut length)?;
+            u64::from_be_bytes(length)
+        }
+        length => length as u64,
+    };
+    let mut mask = None;
+    if head[1] & 0x80 != 0 {
+        let mut key = [0u8; 4];
+        r.read_exact(&mut key)?;
+        mask = Some(key);
+    }
+    Ok((fin, opcode, mask, length))
+}
+
+/// A frame's payload, unmasked, once its header's been read.
+fn read_payload<R: Read + ?Sized>(
+    r: &mut R,
+    mask: [u8; 4],
+    length: u64,
+) -> io::Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    r.take(length).read_to_end(&mut payload)?;
+    if payload.len() as u64 != length {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+    Ok(payload)
+}
+
+fn write_frame<W: Write + ?Sized>(
+    w: &mut W,
+    opcode: u8,
+    payload: &[u8],
+) -> io::Result<()> {
+    let mut head = vec![0x80 | opcode];
+    match payload.len() {
+        length if length < 126 => head.push(length as u8),
+        length if length <= u16::MAX as usize => {
+            head.push(126);
+            head.extend((length as u16).to_be_bytes());
+        }
+        length => {
+            head.push(127);
+            head.extend((length as u64).to_be_bytes());
+        }
+    }
+    w.write_all(&head)?;
+    w.write_all(payload)?;
+    w.flush()
+}
+
+/// Completes a WebSocket upgrade, writing each message received on the
+/// connection to STDOUT, or a worker, until either side closes it.
+fn handle_ws(
+    req: tiny_http::Request,
+    key: &str,
+    principal: Option<String>,
+    connections: Connections,
+    requests: &Requests,
+    options: Arc<HttpOptions>,
+) -> (u16, usize) {
+    if req.secure() {
+        return respond(req, tiny_http::Response::empty(501));
+    }
+
+    let uid = Uuid::new_v4();
+    let url = normalize_url(&req);
+    let path = percent_encoding::percent_decode_str(url.path()).decode_utf8_lossy();
+    let packet = serde_json::json!({
+        "topic": "http.ws.open",
+        "content": {
+            "connection_id": uid,
+            "headers": headers(&req),
+            "remote_addr": req.remote_addr(),
+            "url": req.url(),
+            "normalized_url": url.as_str(),
+            "path": path,
+            "query": query_params(&url),
+            "cookies": cookies(&req),
+            "principal": principal,
+        },
+    });
+
+    let accept = base64::encode(Sha1::digest(format!(
+        "{}258EAFA5-E914-47DA-95CA-C5AB0DC85B11",
+        key
+    )));
+    let response = tiny_http::Response::empty(101).with_header(
+        tiny_http::Header::from_bytes("Sec-WebSocket-Accept", accept).unwrap(),
+    );
+    let mut conn = req.upgrade("websocket", response);
+
+    let handler = match &options.workers {
+        Some(workers) => workers.handler(&requests.lock().expect("poisoned")),
+        None => Handler::Stdout,
+    };
+
+    let (tx, rx) = mpsc::channel::<(u8, Vec<u8>)>();
+    connections
+        .lock()
+        .expect("poisoned")
+        .insert(uid.to_string(), tx);
+    let _ = handler.send(&packet);
+
+    // tiny_http hands out upgraded connections as a single object, which can't be
+    // written while it's being read, nor read with a timeout. So frames for the
+    // client are sent between reading its own, and the client's pinged before
+    // each read: its pong ends a read that would otherwise hold them up. Pings
+    // back off to --keep-alive while the client's idle.
+    let mut code = None;
+    let mut message = Vec::new();
+    let mut text = false;
+    // whether a close frame's been sent, after which only the client's is awaited
+    let mut closing = false;
+    let mut pinged = false;
+    let mut wait = WS_POLL;
+    loop {
+        if !closing {
+            // until the client's answered the last ping, only frames already
+            // queued are sent
+            let deadline = if pinged {
+                time::Instant::now()
+            } else {
+                time::Instant::now() + wait
+            };
+            let mut sent = Ok(());
+            while sent.is_ok() && !closing {
+                let timeout = deadline.saturating_duration_since(time::Instant::now());
+                let (opcode, payload) = match rx.recv_timeout(timeout) {
+                    Ok(frame) => frame,
+                    Err(_) => break,
+                };
+                sent = write_frame(&mut conn, opcode, &payload);
+                closing = opcode == WS_CLOSE;
+            }
+            if sent.is_ok() && !closing && !pinged {
+                sent = write_frame(&mut conn, WS_PING, &[]);
+                pinged = true;
+            }
+            if sent.is_err() {
+                break;
+            }
+        }
+
+        let (fin, opcode, mask, length) = match read_head(&mut conn) {
+            Ok(head) => head,
+            Err(_) => break,
+        };
+        // frames from clients are masked, and control frames are short; messages
+        // larger than --max-body close the connection before they're read
+        let buffered = if opcode == WS_CONTINUATION {
+            message.len()
+        } else {
+            0
+        };
+        let error: Option<u16> = match opcode {
+            _ if mask.is_none() => Some(1002),
+            WS_CLOSE | WS_PING | WS_PONG if length > 125 => Some(1002),
+            WS_CLOSE | WS_PING | WS_PONG => None,
+            WS_TEXT | WS_BINARY | WS_CONTINUATION => match options.max_body {
+                Some(max) if length > max.saturating_sub(buffered) as u64 => Some(1009),
+                _ => None,
+            },
+            _ => Some(1002),
+        };
+        if let Some(error) = error {
+            code = Some(error);
+            if !closing {
+                let _ = write_frame(&mut conn, WS_CLOSE, &error.to_be_bytes());
+            }
+            break;
+        }
+        let payload = match read_payload(&mut conn, mask.unwrap(), length) {
+            Ok(payload) => payload,
+            Err(_) => break,
+        };
+
+        match opcode {
+            WS_PING => {
+                if write_frame(&mut conn, WS_PONG, &payload).is_err() {
+                    break;
+                }
+                continue;
+            }
+            WS_PONG => {
+                pinged = false;
+                wait = (wait * 2).min(options.keep_alive);
+                continue;
+            }
+            WS_CLOSE => {
+                code = payload.get(..2).map(|x| u16::from_be_bytes([x[0], x[1]]));
+                if !closing {
+                    let _ = write_frame(
+                        &mut conn,
+                        WS_CLOSE,
+                        payload.get(..2).unwrap_or(&[]),
+                    );
+                }
+                break;
+            }
+            WS_TEXT | WS_BINARY => {
+                text = opcode == WS_TEXT;
+                message = payload;
+                wait = WS_POLL;
+            }
+            _ => message.extend(payload),
+        }
+        if !fin {
+            continue;
+        }
+
+        let message = std::mem::take(&mut message);
+        let (body, encoding) = if text {
+            match String::from_utf8(message) {
+                Ok(body) => (body, "utf8"),
+                Err(_) => {
+                    code = Some(1007);
+                    if !closing {
+                        let _ = write_frame(&mut conn, WS_CLOSE, &1007u16.to_be_bytes());
+                    }
+                    break;
+                }
+            }
+        } else {
+            (base64::encode_config(message, base64::URL_SAFE), "base64")
+        };
+        let _ = handler.send(&serde_json::json!({
+            "topic": "http.ws.message",
+            "content": {
+                "connection_id": uid,
+                "body": body,
+                "encoding": encoding,
+            },
+        }));
+    }
+
+    connections
+        .lock()
+        .expect("poisoned")
+        .remove(&uid.to_string());
+    let _ = handler.send(&serde_json::json!({
+        "topic": "http.ws.close",
+        "content": {
+            "connection_id": uid,
+            "code": code,
+        },
+    }));
+    (101, 0)
 }
 
 fn run_merge(sock: net::SocketAddr) -> Result<()> {